@@ -1,26 +1,34 @@
 use std::collections::BTreeMap;
 use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, ensure, Context, Result};
-use percent_encoding::percent_decode_str;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use serde_json::Value;
 use tokio::io::BufReader;
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::{mpsc, Mutex};
-use tokio::task;
+use tokio::sync::{Mutex, Notify};
+use tokio::{select, task};
 use tracing::{debug, error, info, warn, Instrument};
 use uriparse::URI;
 
+use self::queue::SendError;
 use crate::instance::{self, Instance, InstanceKey, InstanceMap};
 use crate::lsp::ext::{self, LspMuxOptions, Tag};
 use crate::lsp::jsonrpc::{
     self, Message, Request, RequestId, ResponseError, ResponseSuccess, Version,
 };
 use crate::lsp::transport::{LspReader, LspWriter};
-use crate::lsp::InitializeParams;
+use crate::lsp::{
+    CancelParams, ClientCapabilities, InitializeParams, PositionEncoding, ProgressToken,
+    WorkspaceFolder,
+};
 use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
 
+mod queue;
+
 /// Read first client message and dispatch lsp mux commands
 pub async fn process(
     socket: Stream,
@@ -81,28 +89,122 @@ pub async fn process(
         }
         ext::Request::Status {} => status(instance_map, writer).await,
         ext::Request::Reload { cwd } => reload(cwd, instance_map, writer).await,
+        ext::Request::Restart { cwd } => restart(cwd, instance_map, writer).await,
+        ext::Request::Stop { cwd, pid, all } => stop(cwd, pid, all, instance_map, writer).await,
     }
 }
 
 #[derive(Clone)]
 pub struct Client {
     id: usize,
-    sender: mpsc::Sender<Message>,
+    sender: Arc<queue::Sender>,
+
+    /// Last time the client sent a message
+    ///
+    /// UTC unix timestamp in milliseconds.
+    last_active: Arc<AtomicI64>,
+
+    /// Wakes up `output_task` and asks it to disconnect the client
+    close: Arc<Notify>,
+
+    /// Client was warned about for falling behind since its queue was last
+    /// empty
+    falling_behind: Arc<AtomicBool>,
+
+    /// Capabilities from the client's `initialize` request
+    capabilities: ClientCapabilities,
+
+    /// Encoding of positions in messages to and from the client
+    position_encoding: PositionEncoding,
+}
+
+// Current unix timestamp with millisecond precision
+fn utc_now_millis() -> i64 {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
 }
 
 impl Client {
-    fn new(id: usize) -> (Client, mpsc::Receiver<Message>) {
-        let (sender, receiver) = mpsc::channel(16);
-        (Client { id, sender }, receiver)
+    pub(crate) fn new(
+        id: usize,
+        queue_limit: usize,
+        capabilities: ClientCapabilities,
+        position_encoding: PositionEncoding,
+    ) -> (Client, queue::Receiver) {
+        let (sender, receiver) = queue::channel(queue_limit);
+        let sender = Arc::new(sender);
+        let last_active = Arc::new(AtomicI64::new(utc_now_millis()));
+        let client = Client {
+            id,
+            sender,
+            last_active,
+            close: Arc::new(Notify::new()),
+            falling_behind: Arc::new(AtomicBool::new(false)),
+            capabilities,
+            position_encoding,
+        };
+        (client, receiver)
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
 
-    /// Send a message to the client channel
-    pub async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
-        self.sender.send(message).await
+    pub fn capabilities(&self) -> &ClientCapabilities {
+        &self.capabilities
+    }
+
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.position_encoding
+    }
+
+    /// Mark the client as active
+    fn keep_alive(&self) {
+        self.last_active.store(utc_now_millis(), Ordering::Relaxed);
+    }
+
+    /// When did the client last send a message
+    pub fn last_active(&self) -> i64 {
+        self.last_active.load(Ordering::Relaxed)
+    }
+
+    /// Disconnect the client
+    ///
+    /// Messages already queued for the client are still delivered.
+    pub fn close(&self) {
+        self.close.notify_one();
+    }
+
+    /// Queue a message for the client without waiting
+    ///
+    /// Disconnects the client if its queue overflows.
+    pub fn send_message(&self, message: Message) -> Result<(), SendError> {
+        let result = self.sender.send(message);
+        match result {
+            Ok(()) => {
+                // Warn once each time the queue fills up, the client has
+                // caught up when only the new message is queued.
+                let queued = self.sender.len();
+                if queued <= 1 {
+                    self.falling_behind.store(false, Ordering::Relaxed);
+                } else if queued >= self.sender.capacity() / 2
+                    && !self.falling_behind.swap(true, Ordering::Relaxed)
+                {
+                    warn!(
+                        client_id = self.id,
+                        queued, "client is falling behind reading messages",
+                    );
+                }
+            }
+            Err(SendError::Overflow) => {
+                warn!(
+                    client_id = self.id,
+                    "client is not reading messages, disconnecting"
+                );
+                self.close();
+            }
+            _ => {}
+        }
+        result
     }
 }
 
@@ -126,7 +228,8 @@ async fn reload(
     instance_map: Arc<Mutex<InstanceMap>>,
     mut writer: LspWriter<OwnedWriteHalf>,
 ) -> Result<()> {
-    if let Some(instance) = instance_map.lock().await.get_by_cwd(&cwd) {
+    let instance = instance_map.lock().await.get_by_cwd(&cwd).await;
+    if let Some(instance) = instance {
         instance
             .send_message(Message::Request(Request {
                 jsonrpc: Version,
@@ -163,6 +266,92 @@ async fn reload(
     Ok(())
 }
 
+async fn restart(
+    cwd: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    // Don't hold the map locked while the server is restarting.
+    let instance = instance_map.lock().await.get_by_cwd(&cwd).await;
+    let result = match instance {
+        Some(instance) => instance.request_restart().await,
+        None => {
+            debug!(?cwd, "no instance found for path");
+            Err(anyhow!("no instance found"))
+        }
+    };
+
+    let message = match result {
+        Ok(pid) => Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::RestartResponse { pid }).unwrap(),
+            id: RequestId::Number(0),
+        }),
+        Err(err) => Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: format!("{err:#}"),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        }),
+    };
+    writer
+        .write_message(&message)
+        .await
+        .context("writing response")
+}
+
+async fn stop(
+    cwd: Option<String>,
+    pid: Option<u32>,
+    all: bool,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    // Don't hold the map locked while the servers are shutting down.
+    let map = instance_map.lock().await;
+    let instances = if all {
+        map.all()
+    } else if let Some(pid) = pid {
+        map.get_by_pid(pid).into_iter().collect()
+    } else if let Some(cwd) = &cwd {
+        map.get_by_cwd(cwd).await.into_iter().collect()
+    } else {
+        Vec::new()
+    };
+    drop(map);
+
+    let mut stopped = Vec::new();
+    for instance in instances {
+        stopped.push(instance.stop().await);
+    }
+
+    let message = if stopped.is_empty() && !all {
+        debug!(?cwd, ?pid, "no instance found");
+        Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: "no instance found".into(),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        })
+    } else {
+        Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(ext::StopResponse { instances: stopped }).unwrap(),
+            id: RequestId::Number(0),
+        })
+    };
+    writer
+        .write_message(&message)
+        .await
+        .context("writing response")
+}
+
 /// Find or spawn a language server instance and connect the client to it
 async fn connect(
     client_id: usize,
@@ -178,9 +367,13 @@ async fn connect(
     mut reader: LspReader<BufReader<OwnedReadHalf>>,
     mut writer: LspWriter<OwnedWriteHalf>,
 ) -> Result<()> {
-    // Select the workspace root directory.
+    // Select the workspace folders.
+    let workspace_folders = select_workspace_folders(&init_params, cwd.as_deref())
+        .context("could not get any workspace folders")?;
     let workspace_root = select_workspace_root(&init_params, cwd.as_deref())
-        .context("could not get any workspace_root")?;
+        .context("could not get a workspace root")?;
+
+    let capabilities = init_params.client_capabilities();
 
     // Get an language server instance for this client.
     let key = InstanceKey {
@@ -188,16 +381,58 @@ async fn connect(
         args,
         env,
         workspace_root,
+        workspace_folders: workspace_folders.keys().cloned().collect(),
+        // Filled in by `get_or_spawn` if instances are keyed by options.
+        initialization_options: None,
     };
-    let instance = instance::get_or_spawn(instance_map, key, init_params).await?;
+    let instance = match instance::get_or_spawn(instance_map, key, init_params).await {
+        Ok(instance) => instance,
+        Err(err) => {
+            // Let the editor show the user why the server couldn't start
+            // instead of a closed connection.
+            let res = ResponseError {
+                jsonrpc: Version,
+                error: jsonrpc::Error {
+                    code: jsonrpc::Error::REQUEST_FAILED,
+                    message: format!("ra-multiplex failed to start language server: {err:#}"),
+                    data: None,
+                },
+                id: req.id,
+            };
+            writer
+                .write_message(&res.into())
+                .await
+                .context("send `initialize` request error response")?;
+            return Err(err);
+        }
+    };
+
+    // The server has negotiated its position encoding with the first client,
+    // clients which don't support it get UTF-16 which every client must
+    // support and we translate positions for them.
+    let server_encoding = instance.position_encoding();
+    let position_encoding = match capabilities.position_encodings().contains(&server_encoding) {
+        true => server_encoding,
+        false => PositionEncoding::Utf16,
+    };
+    if position_encoding != server_encoding {
+        info!(
+            ?position_encoding,
+            ?server_encoding,
+            "translating positions for client"
+        );
+    }
 
     // Respond to client's `initialize` request using a response result from
     // the first time this server instance was initialized, it might not be
-    // a response directly to our previous request but it should be hopefully
-    // similar if it comes from another instance of the same client.
+    // a response directly to our previous request so we leave out what this
+    // client doesn't support.
+    let init_result = instance
+        .initialize_result()
+        .for_client(&capabilities, position_encoding);
     let res = ResponseSuccess {
         jsonrpc: Version,
-        result: serde_json::to_value(instance.initialize_result()).unwrap(),
+        result: serde_json::to_value(init_result).unwrap(),
         id: req.id,
     };
     writer
@@ -221,9 +456,18 @@ async fn connect(
     }
     info!("initialized client");
 
-    let (client, client_rx) = Client::new(client_id);
-    task::spawn(input_task(client_rx, writer).in_current_span());
-    instance.add_client(client.clone()).await;
+    let config = instance.config();
+    let (client, client_rx) = Client::new(
+        client_id,
+        config.client_queue_limit,
+        capabilities,
+        position_encoding,
+    );
+    let write_timeout = Duration::from_secs(config.client_write_timeout.into());
+    task::spawn(
+        input_task(client_rx, writer, write_timeout, client.close.clone()).in_current_span(),
+    );
+    instance.add_client(client.clone(), workspace_folders).await;
 
     task::spawn(output_task(reader, client, instance).in_current_span());
 
@@ -231,7 +475,7 @@ async fn connect(
 }
 
 // Parse a file path as String out of a LSP `URI` type.
-fn parse_root_uri(root_uri: &str) -> Result<String> {
+pub fn parse_root_uri(root_uri: &str) -> Result<String> {
     let (scheme, _, mut path, _, _) = URI::try_from(root_uri)
         .context("failed to parse URI")?
         .into_parts();
@@ -261,6 +505,58 @@ fn parse_root_uri(root_uri: &str) -> Result<String> {
     Ok(root)
 }
 
+/// Characters which have to be percent-encoded in the path part of an URI
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// Format a file path as LSP `URI`, the inverse of `parse_root_uri`.
+pub fn path_to_uri(path: &str) -> String {
+    #[cfg(windows)]
+    let path = &path.replace('\\', "/");
+
+    let encoded = utf8_percent_encode(path, PATH_ENCODE_SET);
+    if path.starts_with('/') {
+        format!("file://{encoded}")
+    } else {
+        format!("file:///{encoded}")
+    }
+}
+
+/// Make a workspace folder out of a directory path
+pub fn workspace_folder(path: &str) -> WorkspaceFolder {
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned());
+    WorkspaceFolder {
+        uri: path_to_uri(path),
+        name,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn formatting_path_uris() {
+    assert_eq!(path_to_uri("/home/user/proj"), "file:///home/user/proj");
+    assert_eq!(path_to_uri("c:/dev/proj"), "file:///c:/dev/proj");
+    assert_eq!(
+        path_to_uri("/home/user/my proj"),
+        "file:///home/user/my%20proj"
+    );
+    for path in ["/home/user/proj", "/home/user/my proj#1", "d:/proj", "/"] {
+        assert_eq!(parse_root_uri(&path_to_uri(path)).unwrap(), path);
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn parsing_root_uris() {
@@ -273,26 +569,32 @@ fn parsing_root_uris() {
     assert_eq!(p("file:///e:/").unwrap(), "e:/");
 }
 
-fn select_workspace_root<'a>(
-    init_params: &'a InitializeParams,
-    proxy_cwd: Option<&'a str>,
-) -> Result<String> {
-    if init_params.workspace_folders.len() > 1 {
-        // TODO Ideally we'd be looking up any server which has a superset of
-        // workspace folders active possibly adding transforming the `initialize`
-        // request into a few requests for adding workspace folders if the
-        // server supports it. Buuut let's just run with supporting single-folder
-        // workspaces only at first, it's probably the most common use-case anyway.
-        warn!("initialize request with multiple workspace folders isn't supported");
-        debug!(workspace_folders = ?init_params.workspace_folders);
+/// Select workspace folders keyed by their paths
+fn select_workspace_folders(
+    init_params: &InitializeParams,
+    proxy_cwd: Option<&str>,
+) -> Result<BTreeMap<String, WorkspaceFolder>> {
+    if !init_params.workspace_folders.is_empty() {
+        let mut folders = BTreeMap::new();
+        for (i, folder) in init_params.workspace_folders.iter().enumerate() {
+            let path = parse_root_uri(&folder.uri)
+                .with_context(|| format!("parse initParams.workspaceFolders[{i}].uri"))?;
+            folders.insert(path, folder.clone());
+        }
+        return Ok(folders);
     }
 
-    if init_params.workspace_folders.len() == 1 {
-        return parse_root_uri(&init_params.workspace_folders[0].uri)
-            .context("parse initParams.workspaceFolders[0].uri");
-    }
+    let root = select_workspace_root(init_params, proxy_cwd)?;
+    Ok(BTreeMap::from([(root.clone(), workspace_folder(&root))]))
+}
 
-    assert!(init_params.workspace_folders.is_empty());
+fn select_workspace_root(
+    init_params: &InitializeParams,
+    proxy_cwd: Option<&str>,
+) -> Result<String> {
+    if let Some(folder) = init_params.workspace_folders.first() {
+        return parse_root_uri(&folder.uri).context("parse initParams.workspaceFolders[0].uri");
+    }
 
     // Using the deprecated LSP fields `rootPath` or `rootUri` as fallback
     if let Some(root_uri) = &init_params.root_uri {
@@ -311,22 +613,42 @@ fn select_workspace_root<'a>(
 }
 
 /// Receive messages from channel and write them to the client input socket
-async fn input_task(mut rx: mpsc::Receiver<Message>, mut writer: LspWriter<OwnedWriteHalf>) {
+/// Clients which don't read a message within `write_timeout` are considered
+/// stalled and get disconnected.
+async fn input_task(
+    mut rx: queue::Receiver,
+    mut writer: LspWriter<OwnedWriteHalf>,
+    write_timeout: Duration,
+    close: Arc<Notify>,
+) {
     // The other end of this channel is held by the `output_task` _and_ in the
     // `Instance` itself, this task depends on the `output_task` to detect a
     // client disconnect and call `Instance::cleanup_client`, otherwise we're
     // going to hang forever here.
     while let Some(message) = rx.recv().await {
-        if let Err(err) = writer.write_message(&message).await {
-            match err.kind() {
-                // ignore benign errors, treat as socket close
-                ErrorKind::BrokenPipe => {}
-                // report fatal errors
-                _ => error!(?err, "error writing client input: {err}"),
+        match tokio::time::timeout(write_timeout, writer.write_message(&message)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                match err.kind() {
+                    // ignore benign errors, treat as socket close
+                    ErrorKind::BrokenPipe => {}
+                    // report fatal errors
+                    _ => error!(?err, "error writing client input: {err}"),
+                }
+                break; // break on any error
+            }
+            Err(_) => {
+                warn!(
+                    ?write_timeout,
+                    "client stopped reading messages, disconnecting"
+                );
+                break;
             }
-            break; // break on any error
         }
     }
+    // Make sure `output_task` cleans up after the client if we've stopped
+    // writing to it first.
+    close.notify_one();
     debug!("client input closed");
     info!("client disconnected");
 }
@@ -338,7 +660,14 @@ async fn output_task(
     instance: Arc<Instance>,
 ) {
     loop {
-        let message = match reader.read_message().await {
+        let message = select! {
+            message = reader.read_message() => message,
+            _ = client.close.notified() => {
+                info!("disconnecting client");
+                break;
+            }
+        };
+        let message = match message {
             Ok(Some(message)) => message,
             Ok(None) => {
                 debug!("client output closed");
@@ -350,6 +679,7 @@ async fn output_task(
             }
         };
         instance.keep_alive();
+        client.keep_alive();
 
         match message {
             Message::Request(req) if req.method == "shutdown" => {
@@ -361,12 +691,31 @@ async fn output_task(
                 // <https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#shutdown>
                 let res = ResponseSuccess::null(req.id);
                 // Ignoring error because we would've closed the connection regardless
-                let _ = client.send_message(res.into()).await;
+                let _ = client.send_message(res.into());
                 break;
             }
 
             Message::Request(mut req) => {
+                instance
+                    .translate_from_client(&client, &mut req.params)
+                    .await;
                 req.id = req.id.tag(Tag::ClientId(client.id));
+                // Progress tokens are only unique per client, the server gets
+                // tagged ones to route the `$/progress` back.
+                for key in ["workDoneToken", "partialResultToken"] {
+                    let Some(token) = req.params.get_mut(key) else {
+                        continue;
+                    };
+                    if let Ok(parsed) = serde_json::from_value::<ProgressToken>(token.clone()) {
+                        *token = serde_json::to_value(parsed.tag(client.id)).unwrap();
+                    }
+                }
+                // A restart must not happen between tracking and sending.
+                let _restart = instance.block_restart().await;
+                if !instance.track_request(&client, &req).await {
+                    // Waiting for the response to an identical request.
+                    continue;
+                }
                 if instance.send_message(req.into()).await.is_err() {
                     break;
                 }
@@ -374,6 +723,13 @@ async fn output_task(
 
             Message::ResponseSuccess(mut res) => match res.id.untag() {
                 (Some(Tag::Forward), id) => {
+                    if !instance
+                        .finish_forwarded_request(&id, Some(&res.result))
+                        .await
+                    {
+                        debug!(?res, "server is not waiting for the response");
+                        continue;
+                    }
                     res.id = id;
                     if instance.send_message(res.into()).await.is_err() {
                         break;
@@ -387,8 +743,26 @@ async fn output_task(
                 }
             },
 
-            Message::ResponseError(res) => {
+            Message::ResponseError(mut res) => {
                 warn!(?res, "client responded with error");
+                match res.id.untag() {
+                    (Some(Tag::Forward), id) => {
+                        if !instance.finish_forwarded_request(&id, None).await {
+                            debug!(?res, "server is not waiting for the response");
+                            continue;
+                        }
+                        res.id = id;
+                        if instance.send_message(res.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                    (Some(Tag::Drop), _) => {
+                        // Drop the message
+                    }
+                    _ => {
+                        debug!(?res, "unexpected client response");
+                    }
+                }
             }
 
             Message::Notification(notif) if notif.method == "textDocument/didOpen" => {
@@ -397,13 +771,68 @@ async fn output_task(
                 }
             }
 
+            Message::Notification(notif) if notif.method == "textDocument/didChange" => {
+                if let Err(err) = instance.change_file(&client, notif.params).await {
+                    warn!(?err, "error changing file");
+                }
+            }
+
             Message::Notification(notif) if notif.method == "textDocument/didClose" => {
                 if let Err(err) = instance.close_file(client.id, notif.params).await {
                     warn!(?err, "error closing file");
                 }
             }
 
-            Message::Notification(notif) => {
+            Message::Notification(notif)
+                if notif.method == "workspace/didChangeWorkspaceFolders" =>
+            {
+                if let Err(err) = instance
+                    .change_workspace_folders(client.id, notif.params)
+                    .await
+                {
+                    warn!(?err, "error changing workspace folders");
+                }
+            }
+
+            Message::Notification(notif)
+                if [
+                    "textDocument/didSave",
+                    "workspace/didChangeWatchedFiles",
+                    "workspace/didChangeConfiguration",
+                ]
+                .contains(&notif.method.as_str()) =>
+            {
+                // Files on disk or server settings have changed, cached
+                // responses may be stale now.
+                instance.invalidate_response_cache().await;
+                if instance.send_message(notif.into()).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(mut notif) if notif.method == "$/cancelRequest" => {
+                // The server only knows the tagged request ID.
+                let mut params = match serde_json::from_value::<CancelParams>(notif.params) {
+                    Ok(params) => params,
+                    Err(err) => {
+                        warn!(?err, "error parsing $/cancelRequest params");
+                        continue;
+                    }
+                };
+                if !instance.cancel_request(client.id, &params.id).await {
+                    continue;
+                }
+                params.id = params.id.tag(Tag::ClientId(client.id));
+                notif.params = serde_json::to_value(params).unwrap();
+                if instance.send_message(notif.into()).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(mut notif) => {
+                instance
+                    .translate_from_client(&client, &mut notif.params)
+                    .await;
                 if instance.send_message(notif.into()).await.is_err() {
                     break;
                 }
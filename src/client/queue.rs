@@ -0,0 +1,252 @@
+//! Queue of messages waiting to be written to a client
+//!
+//! Server messages are sent to clients from the single task reading the server
+//! stdout, it must never wait for a slow client or it would hold up all the
+//! other clients and eventually the server itself. Instead each client has its
+//! own queue which never blocks the sender. When a queue fills up we first try
+//! to make room by discarding messages which are safe to lose, if that's not
+//! enough the queue is closed and the client has to be disconnected.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::lsp::jsonrpc::Message;
+
+/// Error returned by [`Sender::send`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// Receiver was dropped or the queue has overflowed before
+    Closed,
+    /// Queue has overflowed and was closed by this message
+    Overflow,
+}
+
+struct Queue {
+    state: Mutex<State>,
+    /// Wakes up the receiver when a message is pushed or the queue is closed
+    notify: Notify,
+    capacity: usize,
+}
+
+struct State {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+/// Create a new queue with the given capacity
+///
+/// The queue is closed when the sender or the receiver is dropped.
+pub fn channel(capacity: usize) -> (Sender, Receiver) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        capacity,
+    });
+    let sender = Sender {
+        queue: queue.clone(),
+    };
+    let receiver = Receiver { queue };
+    (sender, receiver)
+}
+
+impl Queue {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+pub struct Sender {
+    queue: Arc<Queue>,
+}
+
+impl Sender {
+    /// Push a message to the queue without waiting
+    ///
+    /// Older diagnostics for the same document are replaced by newer ones.
+    /// When the queue is full `$/progress` reports are dropped, if there is
+    /// still no room for the message the queue is closed.
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+
+        if let Some(uri) = diagnostics_uri(&message) {
+            state
+                .messages
+                .retain(|queued| diagnostics_uri(queued) != Some(uri));
+        }
+
+        if state.messages.len() >= self.queue.capacity {
+            if is_progress_report(&message) {
+                return Ok(());
+            }
+            state.messages.retain(|queued| !is_progress_report(queued));
+        }
+        if state.messages.len() >= self.queue.capacity {
+            state.closed = true;
+            state.messages.clear();
+            drop(state);
+            self.queue.notify.notify_one();
+            return Err(SendError::Overflow);
+        }
+
+        state.messages.push_back(message);
+        drop(state);
+        self.queue.notify.notify_one();
+        Ok(())
+    }
+
+    /// Number of messages waiting in the queue
+    pub fn len(&self) -> usize {
+        self.queue.state.lock().unwrap().messages.len()
+    }
+
+    /// How many messages can wait in the queue before it overflows
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+pub struct Receiver {
+    queue: Arc<Queue>,
+}
+
+impl Receiver {
+    /// Wait for the next message
+    ///
+    /// Returns `None` when the queue is closed and all remaining messages have
+    /// been received.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.queue.state.lock().unwrap();
+                if let Some(message) = state.messages.pop_front() {
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// Document URI of a `textDocument/publishDiagnostics` notification
+fn diagnostics_uri(message: &Message) -> Option<&str> {
+    match message {
+        Message::Notification(notif) if notif.method == "textDocument/publishDiagnostics" => {
+            notif.params.get("uri")?.as_str()
+        }
+        _ => None,
+    }
+}
+
+/// Is the message a `$/progress` notification which doesn't begin or end the
+/// progress
+fn is_progress_report(message: &Message) -> bool {
+    match message {
+        Message::Notification(notif) if notif.method == "$/progress" => {
+            let kind = notif.params.pointer("/value/kind").and_then(|k| k.as_str());
+            !matches!(kind, Some("begin" | "end"))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{Notification, RequestId, ResponseSuccess, Version};
+
+    fn notification(method: &str, params: serde_json::Value) -> Message {
+        Message::Notification(Notification {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+        })
+    }
+
+    fn diagnostics(uri: &str, version: i64) -> Message {
+        notification(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "version": version, "diagnostics": [] }),
+        )
+    }
+
+    fn progress(kind: &str) -> Message {
+        notification(
+            "$/progress",
+            json!({ "token": 1, "value": { "kind": kind } }),
+        )
+    }
+
+    fn response(id: i64) -> Message {
+        Message::ResponseSuccess(ResponseSuccess::null(RequestId::Number(id)))
+    }
+
+    async fn drain(receiver: &mut Receiver) -> Vec<String> {
+        let mut messages = Vec::new();
+        while let Some(message) = receiver.recv().await {
+            messages.push(format!("{message:?}"));
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn coalesces_diagnostics() {
+        let (sender, mut receiver) = channel(10);
+        sender.send(diagnostics("file:///a", 1)).unwrap();
+        sender.send(diagnostics("file:///b", 1)).unwrap();
+        sender.send(diagnostics("file:///a", 2)).unwrap();
+        drop(sender);
+        let expected = [diagnostics("file:///b", 1), diagnostics("file:///a", 2)]
+            .map(|message| format!("{message:?}"));
+        assert_eq!(drain(&mut receiver).await, expected);
+    }
+
+    #[tokio::test]
+    async fn drops_progress_reports_when_full() {
+        let (sender, mut receiver) = channel(2);
+        sender.send(progress("begin")).unwrap();
+        sender.send(progress("report")).unwrap();
+        // Full, the report is dropped to make room.
+        sender.send(progress("end")).unwrap();
+        // Full, the report is dropped.
+        sender.send(progress("report")).unwrap();
+        drop(sender);
+        let expected = [progress("begin"), progress("end")].map(|message| format!("{message:?}"));
+        assert_eq!(drain(&mut receiver).await, expected);
+    }
+
+    #[tokio::test]
+    async fn closes_on_overflow() {
+        let (sender, mut receiver) = channel(2);
+        sender.send(response(1)).unwrap();
+        sender.send(response(2)).unwrap();
+        assert_eq!(sender.send(response(3)), Err(SendError::Overflow));
+        assert_eq!(sender.send(response(4)), Err(SendError::Closed));
+        assert!(receiver.recv().await.is_none());
+    }
+}
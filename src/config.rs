@@ -1,4 +1,4 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::net::{IpAddr, Ipv4Addr};
 #[cfg(target_family = "unix")]
@@ -43,6 +43,36 @@ mod default {
     pub fn pass_environment() -> BTreeSet<String> {
         BTreeSet::new()
     }
+
+    pub fn shutdown_timeout() -> u32 {
+        // 5 seconds
+        5
+    }
+
+    pub fn client_queue_limit() -> usize {
+        1024
+    }
+
+    pub fn client_write_timeout() -> u32 {
+        // 30 seconds
+        30
+    }
+
+    pub fn response_cache() -> bool {
+        false
+    }
+
+    pub fn match_initialization_options() -> bool {
+        false
+    }
+
+    pub fn workspace_configuration() -> BTreeMap<String, serde_json::Value> {
+        BTreeMap::new()
+    }
+
+    pub fn request_routing() -> RequestRouting {
+        RequestRouting::LastActive
+    }
 }
 
 mod de {
@@ -86,9 +116,24 @@ mod de {
             value => Ok(value),
         }
     }
+
+    /// make sure the queue has room for more than one message, clients are
+    /// warned about when it's half full before they get disconnected
+    pub fn client_queue_limit<'de, D>(deserializer: D) -> Result<usize, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match usize::deserialize(deserializer)? {
+            value @ (0 | 1) => Err(Error::invalid_value(
+                Unexpected::Unsigned(value as u64),
+                &"an integer 2 or greater",
+            )),
+            value => Ok(value),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Address {
     Tcp(IpAddr, u16),
@@ -96,7 +141,20 @@ pub enum Address {
     Unix(PathBuf),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Selects the client which answers server requests meant for the user like
+/// `window/showMessageRequest`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestRouting {
+    /// Client which has most recently sent a message
+    LastActive,
+    /// Client which has been connected the longest
+    FirstConnected,
+    /// Client which has connected most recently
+    LastConnected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default = "default::instance_timeout")]
@@ -107,6 +165,9 @@ pub struct Config {
     #[serde(deserialize_with = "de::gc_interval")]
     pub gc_interval: u32,
 
+    #[serde(default = "default::shutdown_timeout")]
+    pub shutdown_timeout: u32,
+
     #[serde(default = "default::listen")]
     pub listen: Address,
 
@@ -121,6 +182,25 @@ pub struct Config {
 
     #[serde(default = "default::pass_environment")]
     pub pass_environment: BTreeSet<String>,
+
+    #[serde(default = "default::request_routing")]
+    pub request_routing: RequestRouting,
+
+    #[serde(default = "default::client_queue_limit")]
+    #[serde(deserialize_with = "de::client_queue_limit")]
+    pub client_queue_limit: usize,
+
+    #[serde(default = "default::client_write_timeout")]
+    pub client_write_timeout: u32,
+
+    #[serde(default = "default::response_cache")]
+    pub response_cache: bool,
+
+    #[serde(default = "default::match_initialization_options")]
+    pub match_initialization_options: bool,
+
+    #[serde(default = "default::workspace_configuration")]
+    pub workspace_configuration: BTreeMap<String, serde_json::Value>,
 }
 
 #[cfg(test)]
@@ -138,16 +218,32 @@ fn generate_default_and_check_it_matches_commited_defaults() {
     assert_eq!(generated_defaults, saved_defaults);
 }
 
+#[cfg(test)]
+#[test]
+fn reject_too_small_client_queue_limit() {
+    let err = toml::from_str::<Config>("client_queue_limit = 1").unwrap_err();
+    assert!(err.to_string().contains("an integer 2 or greater"));
+    let config = toml::from_str::<Config>("client_queue_limit = 2").unwrap();
+    assert_eq!(config.client_queue_limit, 2);
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
             instance_timeout: default::instance_timeout(),
             gc_interval: default::gc_interval(),
+            shutdown_timeout: default::shutdown_timeout(),
             listen: default::listen(),
             connect: default::connect(),
             log_filters: default::log_filters(),
             log_mode: default::log_mode(),
             pass_environment: default::pass_environment(),
+            request_routing: default::request_routing(),
+            client_queue_limit: default::client_queue_limit(),
+            client_write_timeout: default::client_write_timeout(),
+            response_cache: default::response_cache(),
+            match_initialization_options: default::match_initialization_options(),
+            workspace_configuration: default::workspace_configuration(),
         }
     }
 }
@@ -173,7 +269,7 @@ impl Config {
     pub async fn init_logger(&self) -> Result<()> {
         match self.log_mode.as_str() {
             "file" => self.init_file_logger().await,
-            "terminal" | _ => self.init_terminal_logger(),
+            _ => self.init_terminal_logger(),
         }
     }
 
@@ -0,0 +1,512 @@
+//! Contents of documents opened by clients
+//!
+//! The server only sees a single `textDocument/didOpen` for a document no
+//! matter how many clients have it opened, we keep our own copy of the text so
+//! we can tell what the server currently sees.
+//!
+//! The text is also needed to translate positions between clients and the
+//! server when they count characters in different encodings.
+
+use std::collections::HashMap;
+
+use anyhow::{ensure, Context, Result};
+use serde_json::Value;
+
+use crate::lsp::{
+    DidChangeTextDocumentParams, Position, PositionEncoding, TextDocumentContentChangeEvent,
+    TextDocumentItem,
+};
+
+/// Current state of an opened document
+#[derive(Clone)]
+pub struct Document {
+    pub language_id: String,
+    pub version: u64,
+    pub text: String,
+}
+
+impl Document {
+    /// Apply a single content change
+    ///
+    /// Changes without a range replace the whole document. The range is
+    /// converted from the `from` to the `to` encoding.
+    fn apply_change(
+        &mut self,
+        change: &mut TextDocumentContentChangeEvent,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) -> Result<()> {
+        let Some(range) = &mut change.range else {
+            self.text.clone_from(&change.text);
+            return Ok(());
+        };
+        let start = self.offset(range.start, from);
+        let end = self.offset(range.end, from);
+        ensure!(start <= end, "range start is after its end: {range:?}");
+        if from != to {
+            range.start = self.position(start, to);
+            range.end = self.position(end, to);
+        }
+        self.text.replace_range(start..end, &change.text);
+        Ok(())
+    }
+
+    /// Convert a position to a byte offset into the document text
+    ///
+    /// Positions past the end of a line are clamped to the end of the line
+    /// and positions past the end of the document are clamped to the end of
+    /// the document, like the specification asks.
+    pub fn offset(&self, position: Position, encoding: PositionEncoding) -> usize {
+        let Some(line_start) = self.line_start(position.line) else {
+            return self.text.len();
+        };
+
+        let mut units = 0;
+        for (offset, ch) in self.text[line_start..].char_indices() {
+            if units >= position.character as usize || ch == '\n' || ch == '\r' {
+                return line_start + offset;
+            }
+            units += encoding.char_len(ch);
+        }
+        self.text.len()
+    }
+
+    /// Convert a byte offset into the document text to a position
+    ///
+    /// The offset must be on a character boundary and not between `\r\n`.
+    pub fn position(&self, offset: usize, encoding: PositionEncoding) -> Position {
+        let bytes = self.text.as_bytes();
+        let mut line = 0;
+        let mut line_start = 0;
+        let mut i = 0;
+        while i < offset {
+            if bytes[i] == b'\r' && bytes.get(i + 1) == Some(&b'\n') {
+                i += 1;
+            }
+            if bytes[i] == b'\n' || bytes[i] == b'\r' {
+                line += 1;
+                line_start = i + 1;
+            }
+            i += 1;
+        }
+        let character = self.text[line_start.min(offset)..offset]
+            .chars()
+            .map(|ch| encoding.char_len(ch))
+            .sum::<usize>();
+        Position {
+            line,
+            character: character as u32,
+        }
+    }
+
+    /// Convert a position between encodings
+    fn translate(
+        &self,
+        position: Position,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) -> Position {
+        self.position(self.offset(position, from), to)
+    }
+
+    /// Byte offset of the beginning of a line
+    ///
+    /// Lines can be terminated by `\n`, `\r\n` or `\r`.
+    fn line_start(&self, line: u32) -> Option<usize> {
+        let bytes = self.text.as_bytes();
+        let mut offset = 0;
+        for _ in 0..line {
+            let end = bytes[offset..]
+                .iter()
+                .position(|&b| b == b'\n' || b == b'\r')?;
+            offset += end;
+            offset += match &bytes[offset..] {
+                [b'\r', b'\n', ..] => 2,
+                _ => 1,
+            };
+        }
+        Some(offset)
+    }
+}
+
+/// Documents opened by any client keyed by URI
+#[derive(Default)]
+pub struct DocumentStore {
+    documents: HashMap<String, Document>,
+}
+
+impl DocumentStore {
+    /// Handle `textDocument/didOpen` notification
+    pub fn open(&mut self, item: &TextDocumentItem) {
+        let document = Document {
+            language_id: item.language_id.clone(),
+            version: item.version,
+            text: item.text.clone(),
+        };
+        self.documents.insert(item.uri.clone(), document);
+    }
+
+    /// Handle `textDocument/didChange` notification
+    ///
+    /// Change ranges are in the `from` encoding, they're converted to the `to`
+    /// encoding as the changes are applied.
+    pub fn change(
+        &mut self,
+        params: &mut DidChangeTextDocumentParams,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) -> Result<()> {
+        let uri = &params.text_document.uri;
+        let document = self
+            .documents
+            .get_mut(uri)
+            .with_context(|| format!("document {uri:?} is not opened"))?;
+
+        // Don't leave the document half changed if one of the changes fails.
+        let mut changed = document.clone();
+        for change in &mut params.content_changes {
+            changed.apply_change(change, from, to)?;
+        }
+        changed.version = params.text_document.version;
+        *document = changed;
+        Ok(())
+    }
+
+    /// Handle `textDocument/didClose` notification
+    pub fn close(&mut self, uri: &str) {
+        self.documents.remove(uri);
+    }
+
+    pub fn get(&self, uri: &str) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Document)> {
+        self.documents.iter()
+    }
+
+    /// Convert all positions in a message from the `from` to the `to` encoding
+    ///
+    /// Positions belong to the document of the closest enclosing `uri`,
+    /// `targetUri` or `textDocument.uri` property or to the `uri` argument if
+    /// there's none. Positions in documents which aren't opened are left
+    /// alone, we don't know their text.
+    pub fn translate(
+        &self,
+        value: &mut Value,
+        uri: Option<&str>,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) {
+        if from == to {
+            return;
+        }
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    self.translate(item, uri, from, to);
+                }
+            }
+            Value::Object(object) => {
+                if object.len() == 2 {
+                    if let (Some(line), Some(character)) = (
+                        object.get("line").and_then(Value::as_u64),
+                        object.get("character").and_then(Value::as_u64),
+                    ) {
+                        let Some(document) = uri.and_then(|uri| self.get(uri)) else {
+                            return;
+                        };
+                        let position = Position {
+                            line: line as u32,
+                            character: character as u32,
+                        };
+                        let position = document.translate(position, from, to);
+                        object.insert("line".into(), position.line.into());
+                        object.insert("character".into(), position.character.into());
+                        return;
+                    }
+                }
+
+                let own_uri = object
+                    .get("uri")
+                    .or_else(|| object.get("targetUri"))
+                    .or_else(|| object.get("textDocument")?.get("uri"))
+                    .and_then(Value::as_str)
+                    .map(str::to_owned);
+                let own_uri = own_uri.as_deref().or(uri);
+                for (key, field) in object.iter_mut() {
+                    match (key.as_str(), field) {
+                        // `WorkspaceEdit` text edits keyed by document URI
+                        ("changes", Value::Object(changes)) => {
+                            for (uri, edits) in changes.iter_mut() {
+                                self.translate(edits, Some(uri), from, to);
+                            }
+                        }
+                        // `LocationLink` range in the document of the request
+                        ("originSelectionRange", field) => self.translate(field, uri, from, to),
+                        (_, field) => self.translate(field, own_uri, from, to),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Convert a `SemanticTokens` result from the `from` to the `to` encoding
+    ///
+    /// Tokens are relative to each other and their lengths are counted in the
+    /// position encoding too, they don't look like positions to
+    /// [`DocumentStore::translate`].
+    pub fn translate_semantic_tokens(
+        &self,
+        result: &mut Value,
+        uri: &str,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) {
+        if from == to {
+            return;
+        }
+        let Some(document) = self.get(uri) else {
+            return;
+        };
+        let Some(data) = result.get_mut("data").and_then(Value::as_array_mut) else {
+            return;
+        };
+
+        let (mut line, mut start) = (0, 0);
+        let mut translated_start = 0;
+        for token in data.chunks_exact_mut(5) {
+            let [delta_line, delta_start, length, ..] = token else {
+                unreachable!();
+            };
+            let (Some(dl), Some(ds), Some(len)) =
+                (delta_line.as_u64(), delta_start.as_u64(), length.as_u64())
+            else {
+                return;
+            };
+
+            line += dl as u32;
+            start = if dl == 0 {
+                start + ds as u32
+            } else {
+                ds as u32
+            };
+            let token_start = document.offset(
+                Position {
+                    line,
+                    character: start,
+                },
+                from,
+            );
+            let token_end = document.offset(
+                Position {
+                    line,
+                    character: start + len as u32,
+                },
+                from,
+            );
+            let new_start = document.position(token_start, to).character;
+            let new_end = document.position(token_end, to).character;
+
+            *delta_start = match dl {
+                0 => new_start.saturating_sub(translated_start),
+                _ => new_start,
+            }
+            .into();
+            *length = (new_end - new_start).into();
+            translated_start = new_start;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lsp::PositionEncoding::{Utf16, Utf32, Utf8};
+    use crate::lsp::{Range, VersionedTextDocumentIdentifier};
+
+    fn document(text: &str) -> Document {
+        Document {
+            language_id: "rust".into(),
+            version: 0,
+            text: text.into(),
+        }
+    }
+
+    fn pos(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    fn change(
+        range: Option<((u32, u32), (u32, u32))>,
+        text: &str,
+    ) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: range.map(|(start, end)| Range {
+                start: pos(start.0, start.1),
+                end: pos(end.0, end.1),
+            }),
+            range_length: None,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn offsets() {
+        let doc = document("fn main() {\n    let a = \"ö😀x\";\r\n}\rend");
+        assert_eq!(doc.offset(pos(0, 0), Utf16), 0);
+        assert_eq!(doc.offset(pos(0, 3), Utf16), 3);
+        assert_eq!(doc.offset(pos(1, 13), Utf16), 12 + 13);
+        // `ö` is one UTF-16 code unit but two bytes
+        assert_eq!(doc.offset(pos(1, 14), Utf16), 12 + 15);
+        // `😀` is two UTF-16 code units and four bytes
+        assert_eq!(doc.offset(pos(1, 16), Utf16), 12 + 19);
+        // past the end of the line, before `\r\n`
+        assert_eq!(doc.offset(pos(1, 100), Utf16), 12 + 22);
+        assert_eq!(doc.offset(pos(2, 0), Utf16), 12 + 24);
+        assert_eq!(doc.offset(pos(3, 0), Utf16), 12 + 26);
+        // past the end of the document
+        assert_eq!(doc.offset(pos(3, 100), Utf16), doc.text.len());
+        assert_eq!(doc.offset(pos(10, 0), Utf16), doc.text.len());
+    }
+
+    #[test]
+    fn full_change() {
+        let mut doc = document("old");
+        doc.apply_change(&mut change(None, "new"), Utf16, Utf16)
+            .unwrap();
+        assert_eq!(doc.text, "new");
+    }
+
+    #[test]
+    fn incremental_changes() {
+        let mut doc = document("fn main() {\n    😀\n}\n");
+        doc.apply_change(&mut change(Some(((1, 6), (1, 6))), "!"), Utf16, Utf16)
+            .unwrap();
+        assert_eq!(doc.text, "fn main() {\n    😀!\n}\n");
+        doc.apply_change(&mut change(Some(((0, 3), (0, 7))), "test"), Utf16, Utf16)
+            .unwrap();
+        assert_eq!(doc.text, "fn test() {\n    😀!\n}\n");
+        doc.apply_change(&mut change(Some(((1, 0), (2, 0))), ""), Utf16, Utf16)
+            .unwrap();
+        assert_eq!(doc.text, "fn test() {\n}\n");
+        doc.apply_change(&mut change(Some(((2, 0), (2, 0))), "// end"), Utf16, Utf16)
+            .unwrap();
+        assert_eq!(doc.text, "fn test() {\n}\n// end");
+    }
+
+    #[test]
+    fn invalid_range() {
+        let mut doc = document("abc");
+        assert!(doc
+            .apply_change(&mut change(Some(((0, 2), (0, 1))), ""), Utf16, Utf16)
+            .is_err());
+    }
+
+    #[test]
+    fn positions() {
+        let doc = document("a\r\nö😀x\rend");
+        assert_eq!(doc.position(0, Utf8), pos(0, 0));
+        assert_eq!(doc.position(1, Utf8), pos(0, 1));
+        assert_eq!(doc.position(3, Utf8), pos(1, 0));
+        assert_eq!(doc.position(3 + 2, Utf8), pos(1, 2));
+        assert_eq!(doc.position(3 + 2, Utf16), pos(1, 1));
+        assert_eq!(doc.position(3 + 6, Utf8), pos(1, 6));
+        assert_eq!(doc.position(3 + 6, Utf16), pos(1, 3));
+        assert_eq!(doc.position(3 + 6, Utf32), pos(1, 2));
+        assert_eq!(doc.position(3 + 8, Utf8), pos(2, 0));
+        assert_eq!(doc.position(doc.text.len(), Utf8), pos(2, 3));
+    }
+
+    #[test]
+    fn translated_changes() {
+        let mut doc = document("😀 = 1;\n");
+        let mut change = change(Some(((0, 7), (0, 8))), "2");
+        doc.apply_change(&mut change, Utf8, Utf16).unwrap();
+        assert_eq!(doc.text, "😀 = 2;\n");
+        assert_eq!(change.range.unwrap().start, pos(0, 5));
+        assert_eq!(change.range.unwrap().end, pos(0, 6));
+    }
+
+    #[test]
+    fn translate_message() {
+        let mut store = DocumentStore::default();
+        for uri in ["file:///a", "file:///b"] {
+            store.open(&TextDocumentItem {
+                uri: uri.into(),
+                language_id: "rust".into(),
+                version: 0,
+                text: "😀😀x\n".into(),
+            });
+        }
+        let range = |character| json!({ "start": { "line": 0, "character": character }, "end": { "line": 1, "character": 0 } });
+
+        let mut hover = json!({ "contents": "x", "range": range(4) });
+        store.translate(&mut hover, Some("file:///a"), Utf16, Utf8);
+        assert_eq!(hover, json!({ "contents": "x", "range": range(8) }));
+
+        // Positions in unknown documents are left alone.
+        let mut locations = json!([
+            { "uri": "file:///b", "range": range(2) },
+            { "uri": "file:///c", "range": range(2) },
+        ]);
+        store.translate(&mut locations, Some("file:///a"), Utf16, Utf32);
+        let expected = json!([
+            { "uri": "file:///b", "range": range(1) },
+            { "uri": "file:///c", "range": range(2) },
+        ]);
+        assert_eq!(locations, expected);
+
+        let mut edit =
+            json!({ "changes": { "file:///b": [{ "range": range(8), "newText": "" }] } });
+        store.translate(&mut edit, None, Utf8, Utf16);
+        let expected =
+            json!({ "changes": { "file:///b": [{ "range": range(4), "newText": "" }] } });
+        assert_eq!(edit, expected);
+    }
+
+    #[test]
+    fn translate_semantic_tokens() {
+        let mut store = DocumentStore::default();
+        store.open(&TextDocumentItem {
+            uri: "file:///a".into(),
+            language_id: "rust".into(),
+            version: 0,
+            text: "let 😀 = \"ö\";\nx".into(),
+        });
+        // `let`, `😀`, `"ö"` and `x` in UTF-16
+        let mut tokens =
+            json!({ "data": [0, 0, 3, 0, 0, 0, 4, 2, 1, 0, 0, 5, 3, 2, 0, 1, 0, 1, 1, 0] });
+        store.translate_semantic_tokens(&mut tokens, "file:///a", Utf16, Utf8);
+        let expected =
+            json!({ "data": [0, 0, 3, 0, 0, 0, 4, 4, 1, 0, 0, 7, 4, 2, 0, 1, 0, 1, 1, 0] });
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn failed_change_keeps_document() {
+        let mut store = DocumentStore::default();
+        store.open(&TextDocumentItem {
+            uri: "file:///a".into(),
+            language_id: "rust".into(),
+            version: 1,
+            text: "abc".into(),
+        });
+        let mut params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: "file:///a".into(),
+                version: 2,
+            },
+            content_changes: vec![
+                change(Some(((0, 0), (0, 0))), "x"),
+                change(Some(((0, 2), (0, 1))), ""),
+            ],
+        };
+        assert!(store.change(&mut params, Utf16, Utf16).is_err());
+        let doc = store.get("file:///a").unwrap();
+        assert_eq!((doc.version, doc.text.as_str()), (1, "abc"));
+    }
+}
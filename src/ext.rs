@@ -1,11 +1,12 @@
 use std::env;
+use std::path::Path;
 
 use anyhow::{bail, Context, Result};
 use serde::de::{DeserializeOwned, IgnoredAny};
 use tokio::io::BufReader;
 
 use crate::config::Config;
-use crate::lsp::ext::{self, LspMuxOptions, StatusResponse};
+use crate::lsp::ext::{self, LspMuxOptions, RestartResponse, StatusResponse, StopResponse};
 use crate::lsp::jsonrpc::{Message, Request, RequestId, Version};
 use crate::lsp::transport::{LspReader, LspWriter};
 use crate::lsp::{InitializationOptions, InitializeParams};
@@ -92,7 +93,11 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
                 println!("    {key} = {val}");
             }
         }
-        println!("  path: {:?}", instance.workspace_root);
+        println!("  workspace root: {}", instance.workspace_root);
+        println!("  workspace folders:");
+        for folder in instance.workspace_folders {
+            println!("    - {folder}");
+        }
         let now = time::OffsetDateTime::now_utc().unix_timestamp();
         println!("  last used: {}s ago", now - instance.last_used);
         println!("  registered dynamic capabilities:");
@@ -103,6 +108,10 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
         for client in instance.clients {
             println!("    - Client");
             println!("      id: {}", client.id);
+            println!("      workspace folders:");
+            for folder in client.workspace_folders {
+                println!("        - {}", folder);
+            }
             println!("      files:");
             for file in client.files {
                 println!("        - {}", file);
@@ -112,12 +121,45 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
     Ok(())
 }
 
-pub async fn reload(config: &Config) -> Result<()> {
-    let cwd = env::current_dir()
+fn current_dir() -> Result<String> {
+    Ok(env::current_dir()
         .context("unable to get current_dir")?
         .to_str()
         .context("current_dir is not valid utf-8")?
-        .to_owned();
+        .to_owned())
+}
+
+pub async fn reload(config: &Config) -> Result<()> {
+    let cwd = current_dir()?;
     ext_request::<IgnoredAny>(config, ext::Request::Reload { cwd }).await?;
     Ok(())
 }
+
+pub async fn restart(config: &Config) -> Result<()> {
+    let cwd = current_dir()?;
+    let res = ext_request::<RestartResponse>(config, ext::Request::Restart { cwd }).await?;
+    println!("restarted language server, new pid: {}", res.pid);
+    Ok(())
+}
+
+pub async fn stop(config: &Config, cwd: Option<String>, pid: Option<u32>, all: bool) -> Result<()> {
+    let cwd = match (cwd, pid, all) {
+        (None, None, false) => Some(current_dir()?),
+        (Some(cwd), _, _) => Some(
+            Path::new(&current_dir()?)
+                .join(cwd)
+                .to_str()
+                .context("cwd is not valid utf-8")?
+                .to_owned(),
+        ),
+        (None, _, _) => None,
+    };
+    let res = ext_request::<StopResponse>(config, ext::Request::Stop { cwd, pid, all }).await?;
+    for instance in res.instances {
+        println!(
+            "stopped instance pid: {}, server: {:?} {:?}, workspace folders: {:?}",
+            instance.pid, instance.server, instance.args, instance.workspace_folders,
+        );
+    }
+    Ok(())
+}
@@ -1,67 +1,152 @@
-use std::collections::hash_map::Entry;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::env;
+use std::collections::btree_map::Entry;
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::io::ErrorKind;
 use std::ops::Deref;
 use std::path::Path;
 use std::process::Stdio;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::{env, future, iter, mem};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use serde_json::{json, Value};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify, RwLock, RwLockReadGuard};
 use tokio::{select, task};
 use tracing::{debug, error, field, info, instrument, trace, warn, Instrument};
 
-use crate::client::Client;
-use crate::config::Config;
+use crate::client::{self, Client};
+use crate::config::{Config, RequestRouting};
+use crate::document::DocumentStore;
 use crate::lsp::ext::Tag;
-use crate::lsp::jsonrpc::{Message, Notification, Request, RequestId, ResponseSuccess, Version};
+use crate::lsp::jsonrpc::{
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+};
 use crate::lsp::transport::{LspReader, LspWriter};
-use crate::lsp::{self, ext};
+use crate::lsp::{self, ext, PositionEncoding};
 
 /// Specifies server configuration
 ///
 /// If another server with the same configuration is requested we can reuse it.
+/// Servers supporting workspace folder changes can also be reused by clients
+/// with only partially overlapping `workspace_folders`.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct InstanceKey {
     pub server: String,
     pub args: Vec<String>,
     pub env: BTreeMap<String, String>,
+    /// Directory the language server process is started in, the first
+    /// workspace folder of the client which spawned the instance
     pub workspace_root: String,
+    /// Paths of the workspace folders the instance was spawned with
+    pub workspace_folders: BTreeSet<String>,
+    /// Hash of the `initializationOptions` the instance was spawned with,
+    /// only set with the `match_initialization_options` option
+    pub initialization_options: Option<u64>,
+}
+
+impl InstanceKey {
+    /// Instance is running the same server with the same configuration, only
+    /// the workspace root and folders can differ
+    fn same_server(&self, other: &InstanceKey) -> bool {
+        self.server == other.server
+            && self.args == other.args
+            && self.env == other.env
+            && self.initialization_options == other.initialization_options
+    }
 }
 
 /// Language server instance
 pub struct Instance {
     key: InstanceKey,
 
+    config: Arc<Config>,
+
     /// Language server child process id
-    pid: u32,
+    ///
+    /// Changes when the language server is restarted.
+    pid: AtomicU32,
+
+    /// Parameters of the `initialize` request the server was initialized with
+    init_params: lsp::InitializeParams,
 
     /// Server's response to `initialize` request
     init_result: lsp::InitializeResult,
 
     /// Handle for sending messages to the language server instance
-    server: mpsc::Sender<Message>,
+    ///
+    /// Stays the same when the language server is restarted, `stdin_task`
+    /// switches to writing into the new process.
+    server: mpsc::Sender<StdinMessage>,
+
+    /// Taken for writing while the language server is being replaced
+    ///
+    /// Clients hold it for reading while they track and send a request, so
+    /// every request is either failed by the restart or sent to the new
+    /// server, never both.
+    restart_lock: RwLock<()>,
 
     /// Data of associated clients
     clients: Mutex<HashMap<usize, ClientData>>,
 
+    /// Current contents of documents opened by clients
+    documents: Mutex<DocumentStore>,
+
     /// Dynamic capabilities registered by the server
     dynamic_capabilities: Mutex<HashMap<String, lsp::Registration>>,
 
-    /// Wakes up `wait_task` and asks it to send SIGKILL to the instance.
+    /// Work done progress created by the server which hasn't ended yet
+    work_done_progress: Mutex<HashMap<lsp::ProgressToken, ProgressState>>,
+
+    /// Latest diagnostics published by the server keyed by document URI
+    diagnostics: Mutex<HashMap<String, lsp::PublishDiagnosticsParams>>,
+
+    /// Workspace folders the server is currently working with keyed by path
+    workspace_folders: Mutex<BTreeMap<String, lsp::WorkspaceFolder>>,
+
+    /// Client requests waiting for a server response
+    ///
+    /// Keyed by the tagged request ID as seen by the server.
+    pending_requests: Mutex<HashMap<RequestId, PendingRequest>>,
+
+    /// Server requests forwarded to a single client waiting for its response
+    ///
+    /// Keyed by the untagged server request ID.
+    forwarded_requests: Mutex<HashMap<RequestId, ForwardedRequest>>,
+
+    /// Latest `workspace/configuration` answers from clients keyed by scope
+    /// URI and section
+    ///
+    /// Used to answer the server when no client can.
+    configuration: Mutex<HashMap<ConfigurationKey, Value>>,
+
+    /// Server responses to read-only requests, only used when the
+    /// `response_cache` option is enabled
+    response_cache: Mutex<ResponseCache>,
+
+    /// Wakes up `wait_task` and asks it to shut the instance down
     close: Notify,
 
+    /// Requests for `wait_task` to restart or stop the language server
+    ///
+    /// Closed when `wait_task` exits, the requests it didn't handle are
+    /// dropped.
+    close_requests: mpsc::UnboundedSender<CloseRequest>,
+
+    /// Notified when the server responds to the `shutdown` request
+    shutdown_response: Mutex<Option<oneshot::Sender<()>>>,
+
     /// Last time a message was sent to this instance
     ///
     /// Uses UTC unix timestamp ([utc_now] function)
     last_used: AtomicI64,
+
+    /// Last time the language server process was started
+    ///
+    /// Uses UTC unix timestamp ([utc_now] function)
+    started: AtomicI64,
 }
 
 impl Drop for Instance {
@@ -79,6 +164,9 @@ struct ClientData {
 
     /// URIs of files currently opened by this client
     files: HashSet<String>,
+
+    /// Workspace folders used by this client keyed by path
+    workspace_folders: BTreeMap<String, lsp::WorkspaceFolder>,
 }
 
 impl ClientData {
@@ -86,6 +174,7 @@ impl ClientData {
         ext::Client {
             id: self.client.id(),
             files: self.files.iter().cloned().collect(),
+            workspace_folders: self.workspace_folders.keys().cloned().collect(),
         }
     }
 }
@@ -98,11 +187,150 @@ impl Deref for ClientData {
     }
 }
 
+/// Client request forwarded to the server which hasn't been answered yet
+struct PendingRequest {
+    client_id: usize,
+    method: String,
+    sent: Instant,
+
+    /// Document the request is about, positions in the response without
+    /// their own URI belong to it
+    uri: Option<String>,
+
+    /// Identifies identical requests, `None` if the request can't be
+    /// deduplicated
+    dedup_key: Option<String>,
+
+    /// Identical requests from other clients waiting for the same response
+    ///
+    /// Client IDs with their untagged request IDs.
+    duplicates: Vec<(usize, RequestId)>,
+
+    /// Generation of the response cache when the request was sent, `None` if
+    /// the response must not be cached
+    cache_generation: Option<u64>,
+}
+
+/// Scope URI and section of a `workspace/configuration` item
+type ConfigurationKey = (Option<String>, Option<String>);
+
+/// Server request forwarded to a client which hasn't been answered yet
+struct ForwardedRequest {
+    client_id: usize,
+
+    /// Items of a `workspace/configuration` request, the answers are cached
+    configuration_items: Option<Vec<lsp::ConfigurationItem>>,
+}
+
+/// Results of read-only requests which can be reused until a document changes
+#[derive(Default)]
+struct ResponseCache {
+    /// Incremented whenever the cache is invalidated, responses to requests
+    /// sent before that may be stale and are not cached
+    generation: u64,
+
+    /// Results keyed by the request [`Instance::dedup_key`]
+    results: HashMap<String, Value>,
+
+    /// Keys of `results` from the oldest to the newest
+    order: VecDeque<String>,
+}
+
+impl ResponseCache {
+    /// Save a result, the oldest one is evicted when the cache is full
+    fn insert(&mut self, key: String, result: Value) {
+        if self.results.insert(key.clone(), result).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > RESPONSE_CACHE_LIMIT {
+            let oldest = self.order.pop_front().unwrap();
+            self.results.remove(&oldest);
+        }
+    }
+
+    /// Discard all results
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        self.results.clear();
+        self.order.clear();
+    }
+}
+
+/// Request for `wait_task` to stop the language server
+enum CloseRequest {
+    /// Shut the server down and close the instance, the sender is notified
+    /// when the server has exited
+    Stop(oneshot::Sender<()>),
+
+    /// Shut the server down and start a new one, the new PID is sent back
+    Restart(oneshot::Sender<Result<u32>>),
+}
+
+/// Last known state of a server created work done progress
+#[derive(Default)]
+struct ProgressState {
+    /// Value of the `begin` progress notification
+    begin: Option<Value>,
+
+    /// Value of the latest `report` progress notification
+    report: Option<Value>,
+}
+
+/// Requests whose results are `SemanticTokens`
+const SEMANTIC_TOKENS_REQUESTS: &[&str] = &[
+    "textDocument/semanticTokens/full",
+    "textDocument/semanticTokens/range",
+];
+
+/// Client requests which can cause the server to send a `workspace/applyEdit`
+/// request back while they're being processed
+const EDIT_REQUESTS: &[&str] = &["workspace/executeCommand", "codeAction/resolve"];
+
+/// Read-only document requests which editors sharing a document often send at
+/// the same time, identical requests in flight are sent to the server only once
+/// and their responses can be cached
+const DEDUP_REQUESTS: &[&str] = &[
+    "textDocument/hover",
+    "textDocument/semanticTokens/full",
+    "textDocument/inlayHint",
+    "textDocument/documentSymbol",
+    "textDocument/foldingRange",
+    "textDocument/codeLens",
+];
+
+/// Maximum number of cached responses, the oldest ones are evicted when the
+/// cache is full
+const RESPONSE_CACHE_LIMIT: usize = 1000;
+
+/// Language servers crashing sooner than this many seconds after they were
+/// started are not restarted again to avoid crash loops
+const RESTART_MIN_UPTIME: i64 = 30;
+
 // Current unix timestamp with second precission
 fn utc_now() -> i64 {
     time::OffsetDateTime::now_utc().unix_timestamp()
 }
 
+/// Copy of a `client/registerCapability` or `client/unregisterCapability`
+/// request with only the methods the client can register dynamically
+///
+/// Returns `None` if the client supports none of them.
+fn filter_registrations(client: &Client, req: &Request) -> Option<Request> {
+    let mut req = req.clone();
+    let key = match req.method.as_str() {
+        "client/registerCapability" => "registrations",
+        _ => "unregisterations",
+    };
+    let registrations = req.params.get_mut(key)?.as_array_mut()?;
+    registrations.retain(|reg| {
+        reg["method"]
+            .as_str()
+            .is_some_and(|method| client.capabilities().dynamic_registration(method))
+    });
+    (!registrations.is_empty()).then_some(req)
+}
+
 impl Instance {
     /// Mark the instance as used
     pub fn keep_alive(&self) {
@@ -118,10 +346,68 @@ impl Instance {
         self.init_result.clone()
     }
 
+    /// Position encoding the server has negotiated with the first client
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.init_result.position_encoding()
+    }
+
+    /// Translate positions in client message params to the server's encoding
+    pub async fn translate_from_client(&self, client: &Client, params: &mut Value) {
+        let server_encoding = self.position_encoding();
+        if client.position_encoding() == server_encoding {
+            return;
+        }
+        let uri = params
+            .pointer("/textDocument/uri")
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+        self.documents.lock().await.translate(
+            params,
+            uri.as_deref(),
+            client.position_encoding(),
+            server_encoding,
+        );
+    }
+
+    /// Translate positions in server message params or result to the client's
+    /// encoding
+    ///
+    /// For responses `uri` and `method` come from the request.
+    async fn translate_for_client(
+        &self,
+        client: &Client,
+        value: &mut Value,
+        uri: Option<&str>,
+        method: Option<&str>,
+    ) {
+        let server_encoding = self.position_encoding();
+        if client.position_encoding() == server_encoding {
+            return;
+        }
+        let documents = self.documents.lock().await;
+        match (method, uri) {
+            (Some(method), Some(uri)) if SEMANTIC_TOKENS_REQUESTS.contains(&method) => {
+                documents.translate_semantic_tokens(
+                    value,
+                    uri,
+                    server_encoding,
+                    client.position_encoding(),
+                );
+            }
+            _ => documents.translate(value, uri, server_encoding, client.position_encoding()),
+        }
+    }
+
     /// Add client to the instance so it can receive traffic from it
     ///
-    /// It replays all registered dynamic capabilities to it.
-    pub async fn add_client(&self, client: Client) {
+    /// It replays all registered dynamic capabilities, published diagnostics
+    /// and running work done progress to it and adds its workspace folders to
+    /// the server.
+    pub async fn add_client(
+        &self,
+        client: Client,
+        workspace_folders: BTreeMap<String, lsp::WorkspaceFolder>,
+    ) {
         let mut clients = self.clients.lock().await;
         let dyn_capabilities = self.dynamic_capabilities.lock().await;
 
@@ -139,13 +425,82 @@ impl Instance {
                 params: serde_json::to_value(params).unwrap(),
                 jsonrpc: Version,
             };
+            if let Some(req) = filter_registrations(&client, &req) {
+                debug!(?req, "replaying server request");
+                let _ = client.send_message(req.into());
+            }
+        }
+
+        for params in self.diagnostics.lock().await.values() {
+            let mut notif = Notification {
+                jsonrpc: Version,
+                method: "textDocument/publishDiagnostics".into(),
+                params: serde_json::to_value(params).unwrap(),
+            };
+            self.translate_for_client(&client, &mut notif.params, None, None)
+                .await;
+            trace!(?notif, "replaying server notification");
+            let _ = client.send_message(notif.into());
+        }
+
+        // Clients without work done progress support can't be told about it.
+        let work_done_progress = self.work_done_progress.lock().await;
+        let replay_progress = work_done_progress
+            .iter()
+            .filter(|_| client.capabilities().work_done_progress());
+        for (token, state) in replay_progress {
+            // The client must know the token before any `$/progress` for it,
+            // even if the server hasn't begun the progress yet.
+            let token_json = serde_json::to_string(token).unwrap();
+            let id = RequestId::String(format!("replay:workDoneProgress/create:{token_json}"));
+            let params = lsp::WorkDoneProgressCreateParams {
+                token: token.clone(),
+            };
+            let req = Request {
+                id: id.tag(Tag::Drop),
+                method: "window/workDoneProgress/create".into(),
+                params: serde_json::to_value(params).unwrap(),
+                jsonrpc: Version,
+            };
             debug!(?req, "replaying server request");
-            let _ = client.send_message(req.into()).await;
+            let _ = client.send_message(req.into());
+
+            let Some(mut value) = state.begin.clone() else {
+                continue;
+            };
+            // Update the original `begin` with the latest reported state.
+            if let (Some(value), Some(Value::Object(report))) =
+                (value.as_object_mut(), &state.report)
+            {
+                for (key, field) in report {
+                    if key != "kind" {
+                        value.insert(key.clone(), field.clone());
+                    }
+                }
+            }
+
+            let params = lsp::ProgressParams {
+                token: token.clone(),
+                value,
+            };
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "$/progress".into(),
+                params: serde_json::to_value(params).unwrap(),
+            };
+            debug!(?notif, "replaying server notification");
+            let _ = client.send_message(notif.into());
         }
+        drop(work_done_progress);
+
+        let added = workspace_folders.values().cloned().collect();
+        self.update_workspace_folders(&clients, added, Vec::new())
+            .await;
 
         let client = ClientData {
             client,
             files: HashSet::new(),
+            workspace_folders,
         };
         if clients.insert(client.id(), client).is_some() {
             unreachable!("BUG: added two clients with the same ID");
@@ -159,13 +514,67 @@ impl Instance {
         let mut clients = self.clients.lock().await;
 
         let Some(client) = clients.remove(&client.id()) else {
-            // TODO This happens for example when the language server died while
-            // client was still connected, and the client cleanup is attempted
-            // with the instance being gone already. We should try notifying
-            // these clients immediately and handling the cleanup separately.
-            bail!("client was not connected");
+            // The instance has disconnected the client itself because it's
+            // closing, there is nothing left to clean up.
+            debug!("client was already disconnected");
+            return Ok(());
         };
 
+        // Nobody is going to read the responses, don't let the server waste
+        // time on the requests. Unless other clients are waiting for the same
+        // response.
+        let mut pending_requests = self.pending_requests.lock().await;
+        for req in pending_requests.values_mut() {
+            req.duplicates
+                .retain(|(client_id, _)| *client_id != client.id());
+        }
+        let unwanted = |_: &RequestId, req: &mut PendingRequest| {
+            req.client_id == client.id() && req.duplicates.is_empty()
+        };
+        for (id, req) in pending_requests.extract_if(unwanted) {
+            debug!(?id, method = req.method, "cancelling request");
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "$/cancelRequest".into(),
+                params: serde_json::to_value(lsp::CancelParams { id }).unwrap(),
+            };
+            let _ = self.send_message(notif.into()).await;
+        }
+        drop(pending_requests);
+
+        // The server would be waiting forever for responses from this client
+        let mut forwarded_requests = self.forwarded_requests.lock().await;
+        for (id, req) in forwarded_requests.extract_if(|_, req| req.client_id == client.id()) {
+            let message = match req.configuration_items {
+                Some(items) => ResponseSuccess {
+                    jsonrpc: Version,
+                    result: self.cached_configuration(&items).await,
+                    id,
+                }
+                .into(),
+                None => ResponseError {
+                    jsonrpc: Version,
+                    error: jsonrpc::Error {
+                        code: jsonrpc::Error::REQUEST_FAILED,
+                        message: "client disconnected".into(),
+                        data: None,
+                    },
+                    id,
+                }
+                .into(),
+            };
+            let _ = self.send_message(message).await;
+        }
+        drop(forwarded_requests);
+
+        // Keep the folders of the last client around, the instance is likely
+        // going to be reused by the same workspace again.
+        if !clients.is_empty() {
+            let removed = client.workspace_folders.values().cloned().collect();
+            self.update_workspace_folders(&clients, Vec::new(), removed)
+                .await;
+        }
+
         let files = client.files.into_iter().collect::<Vec<_>>();
         self.close_all_files(&clients, files)
             .await
@@ -175,8 +584,566 @@ impl Instance {
     }
 
     /// Send a message to the language server channel
-    pub async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
-        self.server.send(message).await
+    pub async fn send_message(&self, message: Message) -> Result<()> {
+        let message = StdinMessage::Message(message);
+        ensure!(
+            self.server.send(message).await.is_ok(),
+            "stdin task has exited"
+        );
+        Ok(())
+    }
+
+    /// Keep the language server from being replaced until the guard is dropped
+    pub async fn block_restart(&self) -> RwLockReadGuard<'_, ()> {
+        self.restart_lock.read().await
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid.load(Ordering::Relaxed)
+    }
+
+    /// Restart the language server keeping clients connected
+    ///
+    /// Returns the PID of the new language server process.
+    pub async fn request_restart(&self) -> Result<u32> {
+        let (tx, rx) = oneshot::channel();
+        self.close_requests
+            .send(CloseRequest::Restart(tx))
+            .ok()
+            .context("instance closed")?;
+        rx.await.context("instance closed")?
+    }
+
+    /// Shut the language server down and disconnect all clients
+    ///
+    /// Returns the status of the instance before it was stopped, after the
+    /// language server has exited.
+    pub async fn stop(&self) -> ext::Instance {
+        let status = self.get_status().await;
+        let (tx, rx) = oneshot::channel();
+        if self.close_requests.send(CloseRequest::Stop(tx)).is_ok() {
+            let _ = rx.await;
+        }
+        status
+    }
+
+    /// Respond to all pending client requests with an error
+    ///
+    /// Used when the server is never going to respond to them.
+    async fn fail_pending_requests(
+        &self,
+        clients: &HashMap<usize, ClientData>,
+        error: jsonrpc::Error,
+    ) {
+        let requests = mem::take(&mut *self.pending_requests.lock().await);
+        fail_requests(clients, requests, error);
+    }
+
+    /// Show a message to all clients
+    pub async fn show_message(&self, typ: lsp::MessageType, message: &str) {
+        let params = lsp::ShowMessageParams {
+            typ,
+            message: message.to_owned(),
+        };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "window/showMessage".into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        for client in self.clients.lock().await.values() {
+            let _ = client.send_message(notif.clone().into());
+        }
+    }
+
+    /// Disconnect all clients
+    ///
+    /// Messages already queued for the clients are still delivered before
+    /// their connections are closed.
+    async fn disconnect_clients(&self) {
+        for (_, client) in self.clients.lock().await.drain() {
+            client.close();
+        }
+    }
+
+    /// Does the server accept `workspace/didChangeWorkspaceFolders` notifications
+    fn supports_workspace_folder_changes(&self) -> bool {
+        self.init_result.supports_workspace_folder_changes()
+    }
+
+    /// Handle `workspace/didChangeWorkspaceFolders` client notification
+    pub async fn change_workspace_folders(&self, client_id: usize, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::DidChangeWorkspaceFoldersParams>(params)
+            .context("parsing params")?;
+        let event = params.event;
+
+        let mut clients = self.clients.lock().await;
+        let client = clients.get_mut(&client_id).context("no matching client")?;
+        for folder in &event.removed {
+            let path = client::parse_root_uri(&folder.uri).context("parse removed folder uri")?;
+            client.workspace_folders.remove(&path);
+        }
+        for folder in &event.added {
+            let path = client::parse_root_uri(&folder.uri).context("parse added folder uri")?;
+            client.workspace_folders.insert(path, folder.clone());
+        }
+
+        self.update_workspace_folders(&clients, event.added, event.removed)
+            .await;
+        Ok(())
+    }
+
+    /// Update the server's workspace folders
+    ///
+    /// Only folders the server doesn't know about yet are added and only
+    /// folders which no client in `clients` is using are removed.
+    async fn update_workspace_folders(
+        &self,
+        clients: &HashMap<usize, ClientData>,
+        added: Vec<lsp::WorkspaceFolder>,
+        removed: Vec<lsp::WorkspaceFolder>,
+    ) {
+        if !self.supports_workspace_folder_changes() {
+            // The server keeps the folders it was started with.
+            debug!(
+                ?added,
+                ?removed,
+                "server doesn't support workspace folder changes"
+            );
+            return;
+        }
+
+        let mut folders = self.workspace_folders.lock().await;
+        let mut event = lsp::WorkspaceFoldersChangeEvent {
+            added: Vec::new(),
+            removed: Vec::new(),
+        };
+
+        for folder in removed {
+            let Ok(path) = client::parse_root_uri(&folder.uri) else {
+                continue;
+            };
+            let in_use = clients
+                .values()
+                .any(|client| client.workspace_folders.contains_key(&path));
+            if !in_use {
+                if let Some(folder) = folders.remove(&path) {
+                    event.removed.push(folder);
+                }
+            }
+        }
+        for folder in added {
+            let Ok(path) = client::parse_root_uri(&folder.uri) else {
+                continue;
+            };
+            if let Entry::Vacant(e) = folders.entry(path) {
+                event.added.push(e.insert(folder).clone());
+            }
+        }
+
+        if event.added.is_empty() && event.removed.is_empty() {
+            return;
+        }
+
+        let params = lsp::DidChangeWorkspaceFoldersParams { event };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "workspace/didChangeWorkspaceFolders".into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        debug!(?notif, "changing workspace folders");
+        let _ = self.send_message(notif.into()).await;
+        self.invalidate_response_cache().await;
+    }
+
+    /// Remember a tagged client request until the server responds to it
+    ///
+    /// Returns `false` if the request must not be sent to the server, either
+    /// because it was answered from the response cache or because an identical
+    /// request from another client is already waiting for a response and this
+    /// one will get a copy of it.
+    pub async fn track_request(&self, client: &Client, req: &Request) -> bool {
+        let client_id = client.id();
+        // Progress tokens are specific to the client.
+        let has_progress_token = ["workDoneToken", "partialResultToken"]
+            .into_iter()
+            .any(|key| req.params.get(key).is_some());
+        let dedup_key = match has_progress_token {
+            true => None,
+            false => self.dedup_key(req, client.position_encoding()).await,
+        };
+
+        // Semantic tokens carry a `resultId` for later delta requests, the
+        // server only remembers it for the latest response.
+        let cacheable =
+            self.config.response_cache && !SEMANTIC_TOKENS_REQUESTS.contains(&req.method.as_str());
+        let mut cache_generation = None;
+        if let Some(dedup_key) = dedup_key.as_ref().filter(|_| cacheable) {
+            let cache = self.response_cache.lock().await;
+            if let Some(result) = cache.results.get(dedup_key) {
+                debug!(method = req.method, "answering request from cache");
+                let (_, id) = req.id.untag();
+                let mut res = ResponseSuccess {
+                    jsonrpc: Version,
+                    result: result.clone(),
+                    id,
+                };
+                drop(cache);
+                if let Some(client) = self.clients.lock().await.get(&client_id) {
+                    let uri = req
+                        .params
+                        .pointer("/textDocument/uri")
+                        .and_then(Value::as_str);
+                    self.translate_for_client(client, &mut res.result, uri, Some(&req.method))
+                        .await;
+                    let _ = client.send_message(res.into());
+                }
+                return false;
+            }
+            cache_generation = Some(cache.generation);
+        }
+
+        let mut pending_requests = self.pending_requests.lock().await;
+        if let Some(dedup_key) = &dedup_key {
+            let identical = pending_requests
+                .values_mut()
+                .find(|pending| pending.dedup_key.as_ref() == Some(dedup_key));
+            if let Some(identical) = identical {
+                debug!(method = req.method, "deduplicating request");
+                let (_, id) = req.id.untag();
+                identical.duplicates.push((client_id, id));
+                return false;
+            }
+        }
+
+        let pending = PendingRequest {
+            client_id,
+            method: req.method.clone(),
+            sent: Instant::now(),
+            uri: req
+                .params
+                .pointer("/textDocument/uri")
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+            dedup_key,
+            duplicates: Vec::new(),
+            cache_generation,
+        };
+        pending_requests.insert(req.id.clone(), pending);
+        true
+    }
+
+    /// Key identifying identical requests
+    ///
+    /// Only requests from [`DEDUP_REQUESTS`] for opened documents can be
+    /// deduplicated, they're identical if they have the same method and params
+    /// and were sent for the same document version by clients using the same
+    /// position encoding.
+    async fn dedup_key(&self, req: &Request, encoding: PositionEncoding) -> Option<String> {
+        if !DEDUP_REQUESTS.contains(&req.method.as_str()) {
+            return None;
+        }
+        let uri = req.params.pointer("/textDocument/uri")?.as_str()?;
+        let version = self.documents.lock().await.get(uri)?.version;
+        Some(format!(
+            "{}:{version}:{encoding:?}:{}",
+            req.method, req.params
+        ))
+    }
+
+    /// Forget a tracked request after the server has responded to it
+    ///
+    /// Successful results are saved to the response cache if it wasn't
+    /// invalidated in the meantime. Returns the request, including the
+    /// identical requests which are waiting for the same response.
+    async fn finish_request(
+        &self,
+        id: &RequestId,
+        result: Option<&Value>,
+    ) -> Option<PendingRequest> {
+        let req = self.pending_requests.lock().await.remove(id)?;
+
+        if let (Some(generation), Some(key), Some(result)) =
+            (req.cache_generation, &req.dedup_key, result)
+        {
+            let mut cache = self.response_cache.lock().await;
+            if cache.generation == generation {
+                cache.insert(key.clone(), result.clone());
+            }
+        }
+
+        Some(req)
+    }
+
+    /// Discard all cached responses
+    ///
+    /// Responses to requests which are already waiting for the server won't
+    /// be cached either, they may have been computed before the change.
+    pub async fn invalidate_response_cache(&self) {
+        self.response_cache.lock().await.invalidate();
+    }
+
+    /// Handle `$/cancelRequest` client notification
+    ///
+    /// Returns `false` if the cancellation must not be forwarded to the server
+    /// because other clients are waiting for the response to an identical
+    /// request.
+    pub async fn cancel_request(&self, client_id: usize, id: &RequestId) -> bool {
+        let clients = self.clients.lock().await;
+        let mut pending_requests = self.pending_requests.lock().await;
+
+        let tagged_id = id.tag(Tag::ClientId(client_id));
+        if let Some(req) = pending_requests.get(&tagged_id) {
+            return req.duplicates.is_empty();
+        }
+
+        for req in pending_requests.values_mut() {
+            let Some(index) = req
+                .duplicates
+                .iter()
+                .position(|duplicate| duplicate.0 == client_id && duplicate.1 == *id)
+            else {
+                continue;
+            };
+            req.duplicates.remove(index);
+            if let Some(client) = clients.get(&client_id) {
+                let res = ResponseError {
+                    jsonrpc: Version,
+                    error: jsonrpc::Error {
+                        code: jsonrpc::Error::REQUEST_CANCELLED,
+                        message: "request cancelled".into(),
+                        data: None,
+                    },
+                    id: id.clone(),
+                };
+                let _ = client.send_message(res.into());
+            }
+            return false;
+        }
+
+        true
+    }
+
+    /// Save published diagnostics to allow later replaying them to new clients
+    async fn publish_diagnostics(&self, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::PublishDiagnosticsParams>(params)
+            .context("parsing params")?;
+
+        let mut diagnostics = self.diagnostics.lock().await;
+        if params.diagnostics.is_empty() {
+            diagnostics.remove(&params.uri);
+        } else {
+            diagnostics.insert(params.uri.clone(), params);
+        }
+
+        Ok(())
+    }
+
+    /// Start tracking work done progress created by the server
+    async fn create_progress(&self, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::WorkDoneProgressCreateParams>(params)
+            .context("parsing params")?;
+
+        self.work_done_progress
+            .lock()
+            .await
+            .insert(params.token, ProgressState::default());
+
+        Ok(())
+    }
+
+    /// Save the state of server created work done progress to allow later
+    /// replaying it to new clients
+    async fn update_progress(&self, params: lsp::ProgressParams) {
+        let mut work_done_progress = self.work_done_progress.lock().await;
+        let Some(state) = work_done_progress.get_mut(&params.token) else {
+            return;
+        };
+        match params.value["kind"].as_str() {
+            Some("begin") => {
+                state.begin = Some(params.value);
+                state.report = None;
+            }
+            Some("report") => state.report = Some(params.value),
+            Some("end") => {
+                work_done_progress.remove(&params.token);
+            }
+            _ => {}
+        }
+    }
+
+    /// Forward a server request to a single client and remember which client
+    /// is supposed to respond to it
+    async fn forward_request(&self, client: &ClientData, mut req: Request) {
+        let configuration_items = match req.method.as_str() {
+            "workspace/configuration" => {
+                serde_json::from_value::<lsp::ConfigurationParams>(req.params.clone())
+                    .map(|params| params.items)
+                    .ok()
+            }
+            _ => None,
+        };
+        let forwarded = ForwardedRequest {
+            client_id: client.id(),
+            configuration_items,
+        };
+        self.forwarded_requests
+            .lock()
+            .await
+            .insert(req.id.clone(), forwarded);
+        self.translate_for_client(client, &mut req.params, None, None)
+            .await;
+        req.id = req.id.tag(Tag::Forward);
+        let _ = client.send_message(req.into());
+    }
+
+    /// Forget a forwarded server request after the client has responded to it
+    ///
+    /// Successful answers to `workspace/configuration` are cached. Returns
+    /// `false` if the server isn't waiting for the response, for example
+    /// because it was restarted in the meantime.
+    pub async fn finish_forwarded_request(&self, id: &RequestId, result: Option<&Value>) -> bool {
+        let Some(req) = self.forwarded_requests.lock().await.remove(id) else {
+            return false;
+        };
+        if let (Some(items), Some(Value::Array(values))) = (req.configuration_items, result) {
+            let mut configuration = self.configuration.lock().await;
+            for (item, value) in iter::zip(items, values) {
+                configuration.insert((item.scope_uri, item.section), value.clone());
+            }
+        }
+        true
+    }
+
+    /// Answer `workspace/configuration` items without asking a client
+    ///
+    /// Uses the latest answers from clients for the same scope or without one
+    /// and falls back to the `workspace_configuration` settings for the server
+    /// from our configuration file, items we know nothing about are `null`.
+    async fn cached_configuration(&self, items: &[lsp::ConfigurationItem]) -> Value {
+        let server = Path::new(&self.key.server)
+            .file_name()
+            .and_then(|name| name.to_str());
+        let defaults = self
+            .config
+            .workspace_configuration
+            .get(&self.key.server)
+            .or_else(|| self.config.workspace_configuration.get(server?));
+
+        let configuration = self.configuration.lock().await;
+        let values = items.iter().map(|item| {
+            let answer = configuration
+                .get(&(item.scope_uri.clone(), item.section.clone()))
+                .or_else(|| configuration.get(&(None, item.section.clone())));
+            if let Some(value) = answer {
+                return value.clone();
+            }
+            // Sections are `.` separated paths into the settings.
+            let segments = item.section.iter().flat_map(|section| section.split('.'));
+            segments
+                .fold(defaults, |value, segment| value?.get(segment))
+                .cloned()
+                .unwrap_or_default()
+        });
+        Value::Array(values.collect())
+    }
+
+    /// Send SIGTERM to the language server process
+    #[cfg(unix)]
+    fn terminate_server(&self) -> std::io::Result<()> {
+        let pid = self.pid() as libc::pid_t;
+        // SAFETY: `kill` has no memory safety preconditions. The child is only
+        // reaped by `wait_task` which is the only caller so the PID can't be
+        // reused by an unrelated process yet.
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Send `shutdown` request to the server
+    ///
+    /// The returned receiver completes when the server responds.
+    async fn shutdown_server(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        *self.shutdown_response.lock().await = Some(tx);
+        let req = Request {
+            jsonrpc: Version,
+            method: "shutdown".into(),
+            params: Value::Null,
+            id: RequestId::String("shutdown".into()).tag(Tag::Drop),
+        };
+        let _ = self.send_message(req.into()).await;
+        rx
+    }
+
+    /// Notify `wait_task` if the server responded to the `shutdown` request
+    async fn finish_shutdown(&self, id: &RequestId) {
+        if *id != RequestId::String("shutdown".into()) {
+            return;
+        }
+        if let Some(tx) = self.shutdown_response.lock().await.take() {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Send `exit` notification to the server
+    ///
+    /// Must only be sent after the server responded to `shutdown`.
+    async fn exit_server(&self) {
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "exit".into(),
+            params: Value::Null,
+        };
+        let _ = self.send_message(notif.into()).await;
+    }
+
+    /// Should a crashed language server be replaced with a new process
+    async fn should_restart(&self) -> bool {
+        if self.clients.lock().await.is_empty() {
+            info!("no clients connected, not restarting language server");
+            return false;
+        }
+        let uptime = utc_now() - self.started.load(Ordering::Relaxed);
+        if uptime < RESTART_MIN_UPTIME {
+            warn!(
+                uptime,
+                "language server crashed too soon after start, not restarting"
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Select the client which should answer a server request meant for the
+    /// user according to the `request_routing` configuration
+    fn select_client<'a>(
+        &self,
+        clients: impl Iterator<Item = &'a ClientData>,
+    ) -> Option<&'a ClientData> {
+        match self.config.request_routing {
+            RequestRouting::LastActive => clients.max_by_key(|client| client.last_active()),
+            RequestRouting::FirstConnected => clients.min_by_key(|client| client.id()),
+            RequestRouting::LastConnected => clients.max_by_key(|client| client.id()),
+        }
+    }
+
+    /// Find the client which is most likely expecting a `workspace/applyEdit`
+    ///
+    /// Servers don't say which request caused the edit, we pick the client
+    /// with the most recently sent command which is still being processed.
+    async fn edit_requester(&self) -> Option<usize> {
+        self.pending_requests
+            .lock()
+            .await
+            .values()
+            .filter(|req| EDIT_REQUESTS.contains(&req.method.as_str()))
+            .max_by_key(|req| req.sent)
+            .map(|req| req.client_id)
     }
 
     /// Save registered capabilities to allow later replaying them to new clients
@@ -222,6 +1189,18 @@ impl Instance {
             }
         }
 
+        if !send_notification {
+            let documents = self.documents.lock().await;
+            if let Some(document) = documents.get(uri) {
+                if document.text != params.text_document.text {
+                    warn!(
+                        ?uri,
+                        "client opened file with different contents than the server has"
+                    );
+                }
+            }
+        }
+
         clients
             .get_mut(&client_id)
             .expect("no matching client")
@@ -229,6 +1208,10 @@ impl Instance {
             .insert(uri.clone());
 
         if send_notification {
+            self.documents.lock().await.open(&params.text_document);
+            // Reopened documents can reuse versions of the closed ones.
+            self.invalidate_response_cache().await;
+
             let notif = Notification {
                 jsonrpc: Version,
                 method: "textDocument/didOpen".into(),
@@ -241,6 +1224,45 @@ impl Instance {
         Ok(())
     }
 
+    /// Handle `textDocument/didChange` client notification
+    pub async fn change_file(&self, client: &Client, mut params: Value) -> Result<()> {
+        // Forward the change even if we fail to parse or apply it, the server
+        // may still be able to make sense of it. Keep the store locked until
+        // the change is sent so a restarting server doesn't see it twice.
+        let mut documents = self.documents.lock().await;
+        let result = serde_json::from_value::<lsp::DidChangeTextDocumentParams>(params.clone())
+            .context("parsing params")
+            .and_then(|mut parsed| {
+                let result = documents.change(
+                    &mut parsed,
+                    client.position_encoding(),
+                    self.position_encoding(),
+                );
+                // Only write back the translated ranges, the message may
+                // contain fields we don't know about.
+                for (index, change) in parsed.content_changes.iter().enumerate() {
+                    let pointer = format!("/contentChanges/{index}/range");
+                    if let (Some(range), Some(original)) =
+                        (change.range, params.pointer_mut(&pointer))
+                    {
+                        *original = serde_json::to_value(range).unwrap();
+                    }
+                }
+                result.context("applying changes")
+            });
+        // Any change can affect responses for other documents too.
+        self.invalidate_response_cache().await;
+
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "textDocument/didChange".into(),
+            params,
+        };
+        let _ = self.send_message(notif.into()).await;
+
+        result
+    }
+
     /// Handle `textDocument/didClose` client notification
     pub async fn close_file(&self, client_id: usize, params: Value) -> Result<()> {
         let params = serde_json::from_value::<lsp::DidCloseTextDocumentParams>(params)
@@ -277,6 +1299,8 @@ impl Instance {
             }
 
             if send_notification {
+                self.documents.lock().await.close(&uri);
+
                 let params = lsp::DidCloseTextDocumentParams {
                     text_document: lsp::TextDocumentIdentifier { uri },
                 };
@@ -310,12 +1334,21 @@ impl Instance {
             .map(|reg| reg.method.clone())
             .collect();
 
+        let workspace_folders = self
+            .workspace_folders
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect();
+
         ext::Instance {
-            pid: self.pid,
+            pid: self.pid(),
             server: self.key.server.clone(),
             args: self.key.args.clone(),
             env: self.key.env.clone(),
             workspace_root: self.key.workspace_root.clone(),
+            workspace_folders,
             last_used: self.last_used.load(Ordering::Relaxed),
             clients,
             registered_dyn_capabilities,
@@ -323,11 +1356,17 @@ impl Instance {
     }
 }
 
-pub struct InstanceMap(HashMap<InstanceKey, Arc<Instance>>);
+pub struct InstanceMap {
+    instances: HashMap<InstanceKey, Arc<Instance>>,
+    config: Arc<Config>,
+}
 
 impl InstanceMap {
     pub fn new(config: &Config) -> Arc<Mutex<Self>> {
-        let instance_map = Arc::new(Mutex::new(InstanceMap(HashMap::new())));
+        let instance_map = Arc::new(Mutex::new(InstanceMap {
+            instances: HashMap::new(),
+            config: Arc::new(config.clone()),
+        }));
         task::spawn(gc_task(
             instance_map.clone(),
             config.gc_interval,
@@ -336,19 +1375,76 @@ impl InstanceMap {
         instance_map
     }
 
-    /// Finds an instance with the longest path such as
-    /// `cwd.starts_with(workspace_root)` is true
-    pub fn get_by_cwd(&self, cwd: &str) -> Option<&Instance> {
-        self.0
-            .iter()
-            .filter(|(key, _)| Path::new(cwd).starts_with(&key.workspace_root))
-            .max_by_key(|(key, _)| key.workspace_root.len())
-            .map(|(_, inst)| inst.deref())
+    /// Get all running instances
+    pub fn all(&self) -> Vec<Arc<Instance>> {
+        self.instances.values().cloned().collect()
+    }
+
+    /// Get an instance by its language server PID
+    pub fn get_by_pid(&self, pid: u32) -> Option<Arc<Instance>> {
+        self.instances
+            .values()
+            .find(|instance| instance.pid() == pid)
+            .cloned()
+    }
+
+    /// Remove an instance from the map so new clients spawn their own
+    ///
+    /// Does nothing if the key is already used by another instance.
+    fn remove(&mut self, instance: &Arc<Instance>) {
+        if let hash_map::Entry::Occupied(entry) = self.instances.entry(instance.key.clone()) {
+            if Arc::ptr_eq(entry.get(), instance) {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Finds an instance with the longest workspace folder path such as
+    /// `cwd.starts_with(workspace_folder)` is true
+    pub async fn get_by_cwd(&self, cwd: &str) -> Option<Arc<Instance>> {
+        let mut best = None;
+        for instance in self.instances.values() {
+            for path in instance.workspace_folders.lock().await.keys() {
+                if !Path::new(cwd).starts_with(path) {
+                    continue;
+                }
+                if best.is_none_or(|(len, _)| path.len() > len) {
+                    best = Some((path.len(), instance));
+                }
+            }
+        }
+        best.map(|(_, instance)| instance.clone())
+    }
+
+    /// Finds an instance running the same server as `key` which can be reused
+    /// for `key.workspace_folders`
+    ///
+    /// Instances with exactly the same key are preferred, otherwise we look
+    /// for an instance supporting workspace folder changes which already has
+    /// at least one of the folders open.
+    async fn find_reusable(&self, key: &InstanceKey) -> Option<Arc<Instance>> {
+        if let Some(instance) = self.instances.get(key) {
+            return Some(instance.clone());
+        }
+        for (other, instance) in &self.instances {
+            if !other.same_server(key) || !instance.supports_workspace_folder_changes() {
+                continue;
+            }
+            let folders = instance.workspace_folders.lock().await;
+            if key
+                .workspace_folders
+                .iter()
+                .any(|path| folders.contains_key(path))
+            {
+                return Some(instance.clone());
+            }
+        }
+        None
     }
 
     pub async fn get_status(&self) -> ext::StatusResponse {
-        let mut instances = Vec::with_capacity(self.0.len());
-        for instance in self.0.values() {
+        let mut instances = Vec::with_capacity(self.instances.len());
+        for instance in self.instances.values() {
             instances.push(instance.get_status().await);
         }
         ext::StatusResponse { instances }
@@ -366,16 +1462,16 @@ async fn gc_task(
     loop {
         interval.tick().await;
 
-        for (key, instance) in &instance_map.lock().await.0 {
+        for (key, instance) in &instance_map.lock().await.instances {
             let clients = instance.clients.lock().await;
 
             let idle = instance.idle();
-            debug!(path = ?key.workspace_root, idle, clients = clients.len(), "check instance");
+            debug!(folders = ?key.workspace_folders, idle, clients = clients.len(), "check instance");
 
             if let Some(instance_timeout) = instance_timeout {
                 // Close timed out instance
                 if idle > i64::from(instance_timeout) && clients.is_empty() {
-                    info!(pid = instance.pid, path = ?key.workspace_root, idle, "instance timed out");
+                    info!(pid = instance.pid(), folders = ?key.workspace_folders, idle, "instance timed out");
                     instance.close.notify_one();
                 }
             }
@@ -385,13 +1481,16 @@ async fn gc_task(
 
 /// Find existing or spawn a new language server instance
 ///
-/// The instance is looked up based on `instance_key`. If an existing one is
-/// found then it's returned and `init_req_params` are discarded. If it's
-/// not found a new instance is spawned and initialized using the provided
-/// `init_req_params`, this insance is then inserted into the map and returned.
+/// The instance is looked up based on `instance_key` (see
+/// [`InstanceMap::find_reusable`]) which includes a hash of the
+/// `initializationOptions` if `match_initialization_options` is enabled. If an
+/// existing one is found then it's returned and `init_req_params` are
+/// discarded. If it's not found a new instance is spawned and initialized
+/// using the provided `init_req_params`, this insance is then inserted into
+/// the map and returned.
 pub async fn get_or_spawn(
     map: Arc<Mutex<InstanceMap>>,
-    key: InstanceKey,
+    mut key: InstanceKey,
     init_req_params: lsp::InitializeParams,
 ) -> Result<Arc<Instance>> {
     // We have locked a clone of an Arc of the map, we can assume noone else
@@ -399,30 +1498,112 @@ pub async fn get_or_spawn(
     // doesn't try to lock its copy as well. This is a bit unfortunate code
     // organization but we want to have spawn in a separate tracing context and
     // we want to include `wait_task` in it as well in it as well
-    match map.clone().lock().await.0.entry(key.clone()) {
-        Entry::Occupied(e) => {
-            info!("reusing language server instance");
-            Ok(e.get().clone())
-        }
-        Entry::Vacant(e) => {
-            let instance = spawn(key, init_req_params, map)
-                .await
-                .context("spawning instance")?;
-            e.insert(instance.clone());
-            Ok(instance)
+    let map_clone = map.clone();
+    let mut instances = map_clone.lock().await;
+    let options = init_req_params
+        .initialization_options
+        .clone()
+        .unwrap_or_default();
+    if instances.config.match_initialization_options {
+        key.initialization_options = Some(options.normalized_hash());
+    }
+    if let Some(instance) = instances.find_reusable(&key).await {
+        info!("reusing language server instance");
+        let instance_options = instance
+            .init_params
+            .initialization_options
+            .clone()
+            .unwrap_or_default();
+        let differences = instance_options.diff(&options);
+        if !differences.is_empty() {
+            warn!(
+                ?differences,
+                "client initializationOptions differ from the ones the server was started with"
+            );
         }
+        return Ok(instance);
     }
+    let config = instances.config.clone();
+    let instance = spawn(key.clone(), config, init_req_params, map)
+        .await
+        .context("spawning instance")?;
+    instances.instances.insert(key, instance.clone());
+    Ok(instance)
 }
 
 #[instrument(name = "instance", fields(pid = field::Empty), skip_all, parent = None)]
 async fn spawn(
     key: InstanceKey,
+    config: Arc<Config>,
     init_req_params: lsp::InitializeParams,
     // Caller `get_or_spawn` is holding a lock to the map, we must not try to
     // lock it within this function to not cause deadlock, only spawned tasks
     // are allowed to lock it again.
     map: Arc<Mutex<InstanceMap>>,
 ) -> Result<Arc<Instance>> {
+    let (child, pid, mut reader, mut writer) = start_server(&key)?;
+
+    let init_result = initialize_handshake(init_req_params.clone(), &mut reader, &mut writer)
+        .await
+        .context("server handshake")?;
+
+    info!("initialized server");
+
+    let workspace_folders = key
+        .workspace_folders
+        .iter()
+        .map(|path| (path.clone(), client::workspace_folder(path)))
+        .collect();
+
+    let (message_writer, rx) = mpsc::channel(64);
+    let (close_requests, close_requests_rx) = mpsc::unbounded_channel();
+
+    let instance = Arc::new(Instance {
+        key,
+        config,
+        pid: AtomicU32::new(pid),
+        init_params: init_req_params,
+        init_result,
+        server: message_writer,
+        restart_lock: RwLock::default(),
+        clients: Mutex::default(),
+        documents: Mutex::default(),
+        dynamic_capabilities: Mutex::default(),
+        work_done_progress: Mutex::default(),
+        diagnostics: Mutex::default(),
+        workspace_folders: Mutex::new(workspace_folders),
+        pending_requests: Mutex::default(),
+        forwarded_requests: Mutex::default(),
+        configuration: Mutex::default(),
+        response_cache: Mutex::default(),
+        close: Notify::new(),
+        close_requests,
+        shutdown_response: Mutex::default(),
+        last_used: AtomicI64::new(utc_now()),
+        started: AtomicI64::new(utc_now()),
+    });
+
+    task::spawn(stdout_task(instance.clone(), reader).in_current_span());
+    task::spawn(stdin_task(rx, writer).in_current_span());
+
+    task::spawn(wait_task(instance.clone(), map, child, close_requests_rx).in_current_span());
+
+    Ok(instance)
+}
+
+/// Language server process with its PID and stdout reader and stdin writer
+type StartedServer = (
+    Child,
+    u32,
+    LspReader<BufReader<ChildStdout>>,
+    LspWriter<ChildStdin>,
+);
+
+/// Start the language server process for `key`
+///
+/// Returns the child with its PID and handles for talking to it, language
+/// server stderr is logged by a separately spawned task.
+fn start_server(key: &InstanceKey) -> Result<StartedServer> {
     let mut child = Command::new(&key.server)
         .args(&key.args)
         .envs(&key.env)
@@ -437,7 +1618,8 @@ async fn spawn(
                 args,
                 env,
                 workspace_root,
-            } = &key;
+                ..
+            } = key;
             let path = env
                 .get("PATH")
                 .map(<_>::to_owned)
@@ -460,36 +1642,181 @@ async fn spawn(
     task::spawn(stderr_task(stderr).in_current_span());
 
     let stdout = child.stdout.take().unwrap();
-    let mut reader = LspReader::new(BufReader::new(stdout), "server");
+    let reader = LspReader::new(BufReader::new(stdout), "server");
 
     let stdin = child.stdin.take().unwrap();
-    let mut writer = LspWriter::new(stdin, "server");
+    let writer = LspWriter::new(stdin, "server");
+
+    Ok((child, pid, reader, writer))
+}
+
+/// Respond to client requests with an error
+fn fail_requests(
+    clients: &HashMap<usize, ClientData>,
+    requests: HashMap<RequestId, PendingRequest>,
+    error: jsonrpc::Error,
+) {
+    for (id, req) in requests {
+        let (Some(Tag::ClientId(client_id)), id) = id.untag() else {
+            continue;
+        };
+        for (client_id, id) in iter::once((client_id, id)).chain(req.duplicates) {
+            let Some(client) = clients.get(&client_id) else {
+                continue;
+            };
+            let res = ResponseError {
+                jsonrpc: Version,
+                error: error.clone(),
+                id,
+            };
+            let _ = client.send_message(res.into());
+        }
+    }
+}
 
-    let init_result = initialize_handshake(init_req_params, &mut reader, &mut writer)
+/// Replace a crashed language server with a new process
+///
+/// Clients stay connected to the instance. The new server is initialized with
+/// the original `initialize` request and brought up to date with currently
+/// opened documents and workspace folders. Requests the old server didn't
+/// answer are failed.
+async fn restart(instance: &Arc<Instance>) -> Result<Child> {
+    let (child, pid, mut reader, mut writer) = start_server(&instance.key)?;
+
+    initialize_handshake(instance.init_params.clone(), &mut reader, &mut writer)
         .await
         .context("server handshake")?;
 
-    info!("initialized server");
+    info!("initialized restarted server");
+
+    // Hold the document store until `stdin_task` has the messages bringing
+    // the new server up to date. Changes clients sent before are already
+    // included in the reopened documents, the ones they send after are
+    // queued behind our messages.
+    let restart_lock = instance.restart_lock.write().await;
+    let documents = instance.documents.lock().await;
+    let mut messages = Vec::new();
+
+    // The new server starts with the folders from the original `initialize`
+    // request, bring it up to date with the folders clients are using now.
+    let folders = instance.workspace_folders.lock().await;
+    let event = lsp::WorkspaceFoldersChangeEvent {
+        added: folders
+            .iter()
+            .filter(|(path, _)| !instance.key.workspace_folders.contains(*path))
+            .map(|(_, folder)| folder.clone())
+            .collect(),
+        removed: instance
+            .key
+            .workspace_folders
+            .iter()
+            .filter(|path| !folders.contains_key(*path))
+            .map(|path| client::workspace_folder(path))
+            .collect(),
+    };
+    drop(folders);
+    if !event.added.is_empty() || !event.removed.is_empty() {
+        let params = lsp::DidChangeWorkspaceFoldersParams { event };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "workspace/didChangeWorkspaceFolders".into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        messages.push(notif.into());
+    }
 
-    let (message_writer, rx) = mpsc::channel(64);
+    for (uri, document) in documents.iter() {
+        let params = lsp::DidOpenTextDocumentParams {
+            text_document: lsp::TextDocumentItem {
+                uri: uri.clone(),
+                language_id: document.language_id.clone(),
+                version: document.version,
+                text: document.text.clone(),
+            },
+        };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "textDocument/didOpen".into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        debug!(?uri, "reopening file");
+        messages.push(notif.into());
+    }
 
-    let instance = Arc::new(Instance {
-        key,
-        pid,
-        init_result,
-        server: message_writer,
-        clients: Mutex::default(),
-        dynamic_capabilities: Mutex::default(),
-        close: Notify::new(),
-        last_used: AtomicI64::new(utc_now()),
-    });
+    // The old server is not going to answer the requests sent before the
+    // handoff, the new one answers the requests sent after it.
+    let failed_requests = mem::take(&mut *instance.pending_requests.lock().await);
+    instance
+        .server
+        .send(StdinMessage::Restarted(writer, messages))
+        .await
+        .ok()
+        .context("stdin task has exited")?;
+    drop(documents);
+    drop(restart_lock);
+    instance.pid.store(pid, Ordering::Relaxed);
+    instance.started.store(utc_now(), Ordering::Relaxed);
+
+    // Clean up after the old server before reading messages from the new one.
+    let clients = instance.clients.lock().await;
+    let error = jsonrpc::Error {
+        code: jsonrpc::Error::CONTENT_MODIFIED,
+        message: "language server restarted".into(),
+        data: None,
+    };
+    fail_requests(&clients, failed_requests, error);
+    // And it's not waiting for answers anymore.
+    instance.forwarded_requests.lock().await.clear();
+    instance.invalidate_response_cache().await;
+
+    // End running progress, the new server will create its own.
+    for (token, _) in instance.work_done_progress.lock().await.drain() {
+        let params = lsp::ProgressParams {
+            token,
+            value: json!({ "kind": "end" }),
+        };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "$/progress".into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        for client in clients.values() {
+            if client.capabilities().work_done_progress() {
+                let _ = client.send_message(notif.clone().into());
+            }
+        }
+    }
 
-    task::spawn(stdout_task(instance.clone(), reader).in_current_span());
-    task::spawn(stdin_task(rx, writer).in_current_span());
+    // Unregister capabilities, the new server will register its own.
+    let mut dyn_capabilities = instance.dynamic_capabilities.lock().await;
+    if !dyn_capabilities.is_empty() {
+        let params = lsp::UnregistrationParams {
+            unregistrations: dyn_capabilities
+                .drain()
+                .map(|(id, reg)| lsp::Unregistration {
+                    id,
+                    method: reg.method,
+                })
+                .collect(),
+        };
+        let req = Request {
+            id: RequestId::String("restart:unregisterCapabilities".into()).tag(Tag::Drop),
+            method: "client/unregisterCapability".into(),
+            params: serde_json::to_value(params).unwrap(),
+            jsonrpc: Version,
+        };
+        for client in clients.values() {
+            if let Some(req) = filter_registrations(client, &req) {
+                let _ = client.send_message(req.into());
+            }
+        }
+    }
+    drop(dyn_capabilities);
+    drop(clients);
 
-    task::spawn(wait_task(instance.clone(), map, child).in_current_span());
+    task::spawn(stdout_task(instance.clone(), reader).in_current_span());
 
-    Ok(instance)
+    Ok(child)
 }
 
 #[instrument(skip_all)]
@@ -502,8 +1829,9 @@ async fn initialize_handshake(
 
     // Use the first client's `InitializeParams` to initialize server. We assume
     // all subsequent clients configuration will be somewhat compatible with
-    // whatever the first client negotiated for the same `workspace_root`,
-    // `server` and `args`.
+    // whatever the first client negotiated for the same `workspace_folders`,
+    // `server` and `args`. Clients with different `initializationOptions` only
+    // share the server when `match_initialization_options` is disabled.
     let req = Request {
         jsonrpc: Version,
         method: "initialize".into(),
@@ -567,58 +1895,167 @@ async fn stderr_task(stderr: ChildStderr) {
     }
 }
 
+/// Message for `stdin_task`
+enum StdinMessage {
+    /// Write the message into the language server stdin
+    Message(Message),
+
+    /// The language server was restarted, write the messages bringing it up
+    /// to date and all the following ones into the new server stdin
+    Restarted(LspWriter<ChildStdin>, Vec<Message>),
+}
+
 /// Receive messages from clients' channel and write them into language server stdin
-async fn stdin_task(mut receiver: mpsc::Receiver<Message>, mut writer: LspWriter<ChildStdin>) {
-    // Because we (stdin task) don't keep a reference to `self` it will be dropped when the
-    // child closes and all the clients disconnect including the sender and this receiver
-    // will not keep blocking (unlike in client input task)
+///
+/// The task lives as long as the instance, when the language server is
+/// restarted it's handed the new server stdin. Messages received after the old
+/// server has closed its stdin are dropped, the restart brings the new server
+/// up to date with the current documents and fails the requests.
+async fn stdin_task(mut receiver: mpsc::Receiver<StdinMessage>, writer: LspWriter<ChildStdin>) {
+    // Because we (stdin task) don't keep a reference to the instance the
+    // sender will be dropped with it and this receiver will not keep blocking
+    // (unlike in client input task)
+    let mut writer = Some(writer);
     while let Some(message) = receiver.recv().await {
-        if let Err(err) = writer.write_message(&message).await {
-            match err.kind() {
-                // stdin is closed, no need to log an error
-                ErrorKind::BrokenPipe => {}
-                _ => {
-                    let err = anyhow::Error::from(err);
-                    error!(?err, "error writing to stdin");
+        let messages = match message {
+            StdinMessage::Message(message) => vec![message],
+            StdinMessage::Restarted(new_writer, messages) => {
+                writer = Some(new_writer);
+                messages
+            }
+        };
+        for message in messages {
+            let Some(stdin) = &mut writer else {
+                trace!(
+                    ?message,
+                    "language server stdin is closed, dropping message"
+                );
+                continue;
+            };
+            if let Err(err) = stdin.write_message(&message).await {
+                match err.kind() {
+                    // stdin is closed, no need to log an error
+                    ErrorKind::BrokenPipe => {}
+                    _ => {
+                        let err = anyhow::Error::from(err);
+                        error!(?err, "error writing to stdin");
+                    }
                 }
+                debug!("stdin closed");
+                writer = None;
             }
-            break;
         }
     }
-    debug!("stdin closed");
+}
+
+/// Stage of a language server shutdown
+enum Shutdown {
+    /// `shutdown` was sent, waiting for the server to respond
+    Response(oneshot::Receiver<()>),
+    /// `exit` was sent, waiting for the server to exit
+    Exit,
+    /// SIGTERM was sent
+    Terminate,
+    /// SIGKILL was sent
+    Kill,
 }
 
 /// Wait for child and log when it exits
+///
+/// Closing instances are shut down gracefully, first the server is sent
+/// `shutdown` and after it responds `exit`, then it's sent SIGTERM and finally
+/// SIGKILL. Each stage waits for the configured timeout before the next one. Language servers which crash while
+/// clients are connected are restarted, otherwise the instance is closed.
 async fn wait_task(
     instance: Arc<Instance>,
     instance_map: Arc<Mutex<InstanceMap>>,
     mut child: Child,
+    mut close_requests: mpsc::UnboundedReceiver<CloseRequest>,
 ) {
-    let key = instance.key.clone();
+    let shutdown_timeout = Duration::from_secs(instance.config.shutdown_timeout.into());
+    let mut closing = false;
+    let mut restart_requests = Vec::new();
+    let mut stop_requests = Vec::new();
+    let mut shutdown = None;
     loop {
+        // Resolves when the server responded to `shutdown` or the current
+        // stage has expired.
+        let shutdown_stage = async {
+            match &mut shutdown {
+                Some((Shutdown::Response(response), deadline)) => {
+                    select! {
+                        _ = response => {}
+                        _ = tokio::time::sleep_until(*deadline) => {
+                            warn!("language server didn't respond to shutdown, sending exit anyway");
+                        }
+                    }
+                }
+                Some((Shutdown::Exit | Shutdown::Terminate, deadline)) => {
+                    tokio::time::sleep_until(*deadline).await
+                }
+                _ => future::pending().await,
+            }
+        };
+        let close_request = async {
+            select! {
+                _ = instance.close.notified() => None,
+                request = close_requests.recv() => request,
+            }
+        };
         select! {
-            _ = instance.close.notified() => {
-                if let Err(err) = child.start_kill() {
-                    error!(?err, "failed to close child");
+            request = close_request => {
+                match request {
+                    Some(CloseRequest::Restart(tx)) => restart_requests.push(tx),
+                    Some(CloseRequest::Stop(tx)) => {
+                        closing = true;
+                        stop_requests.push(tx);
+                    }
+                    None => closing = true,
                 }
+                if closing {
+                    // Don't let new clients connect to a closing instance.
+                    instance_map.lock().await.remove(&instance);
+                    instance.disconnect_clients().await;
+                }
+                if shutdown.is_none() {
+                    info!("shutting down language server");
+                    let response = instance.shutdown_server().await;
+                    let deadline = tokio::time::Instant::now() + shutdown_timeout;
+                    shutdown = Some((Shutdown::Response(response), deadline));
+                }
+            }
+            _ = shutdown_stage => {
+                let deadline = tokio::time::Instant::now() + shutdown_timeout;
+                shutdown = match shutdown {
+                    Some((Shutdown::Response(_), _)) => {
+                        instance.exit_server().await;
+                        Some((Shutdown::Exit, deadline))
+                    }
+                    #[cfg(unix)]
+                    Some((Shutdown::Exit, _)) => {
+                        warn!("language server didn't exit after shutdown, terminating it");
+                        if let Err(err) = instance.terminate_server() {
+                            error!(?err, "failed to terminate child");
+                        }
+                        Some((Shutdown::Terminate, deadline))
+                    }
+                    _ => {
+                        warn!("language server didn't exit, killing it");
+                        if let Err(err) = child.start_kill() {
+                            error!(?err, "failed to kill child");
+                        }
+                        Some((Shutdown::Kill, deadline))
+                    }
+                };
             }
             exit = child.wait() => {
-                // Remove the closing instance from the map so new clients spawn their own instance
-                instance_map.lock().await.0.remove(&key);
-
-                // Disconnect all current clients
-                //
-                // We'll rely on the editor client to restart the ra-multiplex client,
-                // start a new connection and we'll spawn another instance like we'd with
-                // any other new client.
-                instance.clients.lock().await.clear();
-
-                match exit {
+                shutdown = None;
+                let (exit_message, exit_data) = match exit {
                     Ok(status) => {
                         #[cfg(unix)]
                         let signal = std::os::unix::process::ExitStatusExt::signal(&status);
                         #[cfg(not(unix))]
-                        let signal = tracing::field::Empty;
+                        let signal: Option<i32> = None;
 
                         error!(
                             success = status.success(),
@@ -626,8 +2063,77 @@ async fn wait_task(
                             signal,
                             "child exited",
                         );
+                        (
+                            format!("language server exited unexpectedly ({status})"),
+                            json!({ "code": status.code(), "signal": signal }),
+                        )
+                    }
+                    Err(err) => {
+                        error!(?err, "error waiting for child");
+                        (
+                            format!("language server failed: {err}"),
+                            json!({ "error": err.to_string() }),
+                        )
+                    }
+                };
+
+                // Restarts requested while the instance is closing are dropped.
+                if !closing && !restart_requests.is_empty() {
+                    info!("restarting language server on request");
+                    match restart(&instance).await {
+                        Ok(new_child) => {
+                            child = new_child;
+                            for tx in restart_requests.drain(..) {
+                                let _ = tx.send(Ok(instance.pid()));
+                            }
+                            continue;
+                        }
+                        Err(err) => {
+                            error!(?err, "failed to restart language server");
+                            for tx in restart_requests.drain(..) {
+                                let _ = tx.send(Err(anyhow!("{err:#}")));
+                            }
+                        }
                     }
-                    Err(err) => error!(?err, "error waiting for child"),
+                } else if !closing && instance.should_restart().await {
+                    match restart(&instance).await {
+                        Ok(new_child) => {
+                            child = new_child;
+                            let message = format!("{exit_message}, restarted it");
+                            instance.show_message(lsp::MessageType::WARNING, &message).await;
+                            continue;
+                        }
+                        Err(err) => error!(?err, "failed to restart language server"),
+                    }
+                }
+
+                // Remove the closing instance from the map so new clients spawn their own instance
+                instance_map.lock().await.remove(&instance);
+
+                // Don't leave clients waiting for responses which are never
+                // going to come. Closing instances have disconnected their
+                // clients already.
+                let clients = instance.clients.lock().await;
+                let error = jsonrpc::Error {
+                    code: jsonrpc::Error::INTERNAL_ERROR,
+                    message: exit_message.clone(),
+                    data: Some(exit_data),
+                };
+                instance.fail_pending_requests(&clients, error).await;
+                drop(clients);
+                if !closing {
+                    instance.show_message(lsp::MessageType::ERROR, &exit_message).await;
+                }
+
+                // Disconnect all current clients
+                //
+                // We'll rely on the editor client to restart the ra-multiplex client,
+                // start a new connection and we'll spawn another instance like we'd with
+                // any other new client.
+                instance.disconnect_clients().await;
+
+                for tx in stop_requests {
+                    let _ = tx.send(());
                 }
                 break;
             }
@@ -654,19 +2160,49 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
         let clients = instance.clients.lock().await;
         match message {
             Message::ResponseSuccess(mut res) => {
+                let req = instance.finish_request(&res.id, Some(&res.result)).await;
+                let (method, uri, duplicates) = match req {
+                    Some(req) => (Some(req.method), req.uri, req.duplicates),
+                    None => (None, None, Vec::new()),
+                };
+
                 // Forward successful response to the right client based on the
                 // Request ID tag.
                 match res.id.untag() {
                     (Some(Tag::ClientId(client_id)), id) => {
+                        for (client_id, id) in duplicates {
+                            if let Some(client) = clients.get(&client_id) {
+                                let mut res = res.clone();
+                                res.id = id;
+                                instance
+                                    .translate_for_client(
+                                        client,
+                                        &mut res.result,
+                                        uri.as_deref(),
+                                        method.as_deref(),
+                                    )
+                                    .await;
+                                let _ = client.send_message(res.into());
+                            }
+                        }
                         res.id = id;
                         if let Some(client) = clients.get(&client_id) {
-                            let _ = client.send_message(res.into()).await;
+                            instance
+                                .translate_for_client(
+                                    client,
+                                    &mut res.result,
+                                    uri.as_deref(),
+                                    method.as_deref(),
+                                )
+                                .await;
+                            let _ = client.send_message(res.into());
                         } else {
                             debug!(?client_id, "no matching client");
                         }
                     }
-                    (Some(Tag::Drop), _) => {
+                    (Some(Tag::Drop), id) => {
                         // Drop the message
+                        instance.finish_shutdown(&id).await;
                     }
                     _ => {
                         warn!(?res, "ignoring improperly tagged server response")
@@ -675,20 +2211,34 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
             }
 
             Message::ResponseError(mut res) => {
+                let duplicates = instance
+                    .finish_request(&res.id, None)
+                    .await
+                    .map(|req| req.duplicates)
+                    .unwrap_or_default();
+
                 // Forward the error response to the right client based on the
                 // Request ID tag.
                 match res.id.untag() {
                     (Some(Tag::ClientId(client_id)), id) => {
-                        warn!(?res, "server responded with error");
+                        for (client_id, id) in duplicates {
+                            if let Some(client) = clients.get(&client_id) {
+                                let mut res = res.clone();
+                                res.id = id;
+                                let _ = client.send_message(res.into());
+                            }
+                        }
                         res.id = id;
                         if let Some(client) = clients.get(&client_id) {
-                            let _ = client.send_message(res.into()).await;
+                            warn!(?res, "server responded with error");
+                            let _ = client.send_message(res.into());
                         } else {
                             debug!(?client_id, "no matching client");
                         }
                     }
-                    (Some(Tag::Drop), _) => {
+                    (Some(Tag::Drop), id) => {
                         // Drop the message
+                        instance.finish_shutdown(&id).await;
                     }
                     _ => {
                         warn!(?res, "ignoring improperly tagged server response")
@@ -713,11 +2263,32 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 // client responses.
                 trace!(?req, "server request {}", req.method.as_str());
 
+                // Whatever the server wants refreshed has changed.
+                if req.method != "window/workDoneProgress/create" {
+                    instance.invalidate_response_cache().await;
+                }
+
                 let id = req.id;
                 req.id = id.tag(Tag::Drop);
 
                 for client in clients.values() {
-                    let _ = client.send_message(req.clone().into()).await;
+                    let supported = match req.method.as_str() {
+                        "window/workDoneProgress/create" => {
+                            client.capabilities().work_done_progress()
+                        }
+                        method => client.capabilities().refresh_support(method),
+                    };
+                    if supported {
+                        let _ = client.send_message(req.clone().into());
+                    }
+                }
+
+                // We need to track the progress for any client that might come
+                // while it's still running.
+                if req.method == "window/workDoneProgress/create" {
+                    if let Err(err) = instance.create_progress(req.params).await {
+                        warn!(?err, "error creating progress");
+                    }
                 }
 
                 let _ = instance
@@ -725,18 +2296,39 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                     .await;
             }
 
-            Message::Request(mut req) if req.method == "workspace/configuration" => {
+            Message::Request(req) if req.method == "workspace/configuration" => {
                 // Response to `workspace/configuration` should be the same from
                 // any client. So we'll just pick the first and let it answer.
                 debug!(?req, "server request workspace/configuration");
 
-                req.id = req.id.tag(Tag::Forward);
-
-                if let Some(client) = clients.values().next() {
-                    let _ = client.send_message(req.into()).await;
+                let client = clients
+                    .values()
+                    .find(|client| client.capabilities().configuration());
+                if let Some(client) = client {
+                    instance.forward_request(client, req).await;
                 } else {
-                    // If there is no client connected at this moment we'll
-                    // ignore the request.
+                    // Nobody can answer, the server would be waiting forever.
+                    // Answer with what clients have told us before.
+                    let message =
+                        match serde_json::from_value::<lsp::ConfigurationParams>(req.params) {
+                            Ok(params) => ResponseSuccess {
+                                jsonrpc: Version,
+                                result: instance.cached_configuration(&params.items).await,
+                                id: req.id,
+                            }
+                            .into(),
+                            Err(err) => ResponseError {
+                                jsonrpc: Version,
+                                error: jsonrpc::Error {
+                                    code: jsonrpc::Error::INVALID_PARAMS,
+                                    message: format!("invalid params: {err}"),
+                                    data: None,
+                                },
+                                id: req.id,
+                            }
+                            .into(),
+                        };
+                    let _ = instance.send_message(message).await;
                 }
             }
 
@@ -751,7 +2343,9 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 req.id = id.tag(Tag::Drop);
 
                 for client in clients.values() {
-                    let _ = client.send_message(req.clone().into()).await;
+                    if let Some(req) = filter_registrations(client, &req) {
+                        let _ = client.send_message(req.into());
+                    }
                 }
 
                 // We need to cache the dynamic capabilities registrations for
@@ -776,7 +2370,9 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                 req.id = id.tag(Tag::Drop);
 
                 for client in clients.values() {
-                    let _ = client.send_message(req.clone().into()).await;
+                    if let Some(req) = filter_registrations(client, &req) {
+                        let _ = client.send_message(req.into());
+                    }
                 }
 
                 // We need to remove this registration from the cache so we
@@ -790,20 +2386,377 @@ async fn stdout_task(instance: Arc<Instance>, mut reader: LspReader<BufReader<Ch
                     .await;
             }
 
+            Message::Request(req) if req.method == "workspace/applyEdit" => {
+                // Edits are usually a side effect of a client executing a
+                // command, only the client which requested it should apply
+                // them. The client's response is forwarded back to the server.
+                debug!(?req, "server request workspace/applyEdit");
+
+                let client = match instance.edit_requester().await {
+                    Some(client_id) => clients
+                        .get(&client_id)
+                        .filter(|client| client.capabilities().apply_edit()),
+                    None => None,
+                };
+                if let Some(client) = client {
+                    instance.forward_request(client, req).await;
+                } else {
+                    // Nobody is waiting for the edit, refuse it rather than
+                    // applying it in a random editor.
+                    let result = lsp::ApplyWorkspaceEditResult {
+                        applied: false,
+                        failure_reason: Some("no client is executing a command".into()),
+                    };
+                    let res = ResponseSuccess {
+                        jsonrpc: Version,
+                        result: serde_json::to_value(result).unwrap(),
+                        id: req.id,
+                    };
+                    let _ = instance.send_message(res.into()).await;
+                }
+            }
+
+            Message::Request(req)
+                if ["window/showMessageRequest", "window/showDocument"]
+                    .contains(&req.method.as_str()) =>
+            {
+                // These ask the user to take an action, one editor is enough
+                // to ask. The client's answer is forwarded back to the server.
+                debug!(?req, "server request {}", req.method.as_str());
+
+                let candidates = clients.values().filter(|client| {
+                    req.method != "window/showDocument" || client.capabilities().show_document()
+                });
+                if let Some(client) = instance.select_client(candidates) {
+                    instance.forward_request(client, req).await;
+                } else {
+                    // Nobody to ask, respond as if the user dismissed it.
+                    let result = match req.method.as_str() {
+                        "window/showDocument" => json!({ "success": false }),
+                        _ => Value::Null,
+                    };
+                    let res = ResponseSuccess {
+                        jsonrpc: Version,
+                        result,
+                        id: req.id,
+                    };
+                    let _ = instance.send_message(res.into()).await;
+                }
+            }
+
+            Message::Request(req) if req.method == "workspace/workspaceFolders" => {
+                // We're keeping track of the folders ourselves, there is no
+                // need to ask any client.
+                debug!(?req, "server request workspace/workspaceFolders");
+
+                let folders = instance
+                    .workspace_folders
+                    .lock()
+                    .await
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let res = ResponseSuccess {
+                    jsonrpc: Version,
+                    result: serde_json::to_value(folders).unwrap(),
+                    id: req.id,
+                };
+                let _ = instance.send_message(res.into()).await;
+            }
+
             Message::Request(req) => {
-                // Unimplemented server -> client requests I've found in the LSP Spec.
-                // TODO workspace/workspaceFolders request
-                // TODO workspace/applyEdit request
                 debug!(message = ?req, "ignoring unknown server request");
             }
 
+            Message::Notification(mut notif) if notif.method == "$/cancelRequest" => {
+                // Server is cancelling its own request, only the client we've
+                // forwarded it to knows about it and it knows it by the tagged
+                // ID.
+                let mut params = match serde_json::from_value::<lsp::CancelParams>(notif.params) {
+                    Ok(params) => params,
+                    Err(err) => {
+                        warn!(?err, "error parsing $/cancelRequest params");
+                        continue;
+                    }
+                };
+                let client_id = instance
+                    .forwarded_requests
+                    .lock()
+                    .await
+                    .get(&params.id)
+                    .map(|req| req.client_id);
+                if let Some(client) = client_id.and_then(|id| clients.get(&id)) {
+                    params.id = params.id.tag(Tag::Forward);
+                    notif.params = serde_json::to_value(params).unwrap();
+                    let _ = client.send_message(notif.into());
+                } else {
+                    debug!(id = ?params.id, "server cancelled request no client is processing");
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "$/progress" => {
+                // Progress for tokens supplied by a client with its request is
+                // only interesting to that client, the rest is broadcast.
+                let params = serde_json::from_value::<lsp::ProgressParams>(notif.params.clone())
+                    .inspect_err(|err| warn!(?err, "error parsing $/progress params"))
+                    .ok();
+                let owner = params.as_ref().and_then(|params| params.token.untag());
+                if let Some((client_id, token)) = owner {
+                    // Partial results can contain positions.
+                    if let Some(client) = clients.get(&client_id) {
+                        let mut notif = notif;
+                        notif.params["token"] = serde_json::to_value(token).unwrap();
+                        instance
+                            .translate_for_client(client, &mut notif.params, None, None)
+                            .await;
+                        let _ = client.send_message(notif.into());
+                    }
+                } else {
+                    for client in clients.values() {
+                        if client.capabilities().work_done_progress() {
+                            let _ = client.send_message(notif.clone().into());
+                        }
+                    }
+                    if let Some(params) = params {
+                        instance.update_progress(params).await;
+                    }
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "textDocument/publishDiagnostics" => {
+                for client in clients.values() {
+                    let mut notif = notif.clone();
+                    instance
+                        .translate_for_client(client, &mut notif.params, None, None)
+                        .await;
+                    let _ = client.send_message(notif.into());
+                }
+
+                // We need to cache the diagnostics for any client that might
+                // come later.
+                if let Err(err) = instance.publish_diagnostics(notif.params).await {
+                    warn!(?err, "error caching diagnostics");
+                }
+            }
+
             Message::Notification(notif) => {
                 // Server notifications don't expect a response. We can forward
                 // them to all clients.
                 for client in clients.values() {
-                    let _ = client.send_message(notif.clone().into()).await;
+                    let mut notif = notif.clone();
+                    instance
+                        .translate_for_client(client, &mut notif.params, None, None)
+                        .await;
+                    let _ = client.send_message(notif.into());
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///ws/main.rs";
+
+    async fn instance(config: Config) -> Instance {
+        let (server, _) = mpsc::channel(1);
+        let (close_requests, _) = mpsc::unbounded_channel();
+        let instance = Instance {
+            key: InstanceKey {
+                server: "server".into(),
+                args: Vec::new(),
+                env: BTreeMap::new(),
+                workspace_root: "/ws".into(),
+                workspace_folders: BTreeSet::from(["/ws".into()]),
+                initialization_options: None,
+            },
+            config: Arc::new(config),
+            pid: AtomicU32::new(1),
+            init_params: serde_json::from_value(json!({ "processId": null, "rootUri": null }))
+                .unwrap(),
+            init_result: serde_json::from_value(json!({ "capabilities": {} })).unwrap(),
+            server,
+            restart_lock: RwLock::default(),
+            clients: Mutex::default(),
+            documents: Mutex::default(),
+            dynamic_capabilities: Mutex::default(),
+            work_done_progress: Mutex::default(),
+            diagnostics: Mutex::default(),
+            workspace_folders: Mutex::default(),
+            pending_requests: Mutex::default(),
+            forwarded_requests: Mutex::default(),
+            configuration: Mutex::default(),
+            response_cache: Mutex::default(),
+            close: Notify::new(),
+            close_requests,
+            shutdown_response: Mutex::default(),
+            last_used: AtomicI64::new(utc_now()),
+            started: AtomicI64::new(utc_now()),
+        };
+        let item =
+            json!({ "uri": URI, "languageId": "rust", "version": 1, "text": "fn main() {}" });
+        instance
+            .documents
+            .lock()
+            .await
+            .open(&serde_json::from_value(item).unwrap());
+        instance
+    }
+
+    fn client(id: usize, encoding: PositionEncoding) -> Client {
+        Client::new(id, 16, Default::default(), encoding).0
+    }
+
+    fn request(client: &Client, id: i64, method: &str) -> Request {
+        Request {
+            jsonrpc: Version,
+            method: method.into(),
+            params: json!({
+                "textDocument": { "uri": URI },
+                "position": { "line": 0, "character": 3 },
+            }),
+            id: RequestId::Number(id).tag(Tag::ClientId(client.id())),
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_requests_are_sent_once() {
+        let instance = instance(Config::default()).await;
+        let a = client(1, PositionEncoding::Utf16);
+        let b = client(2, PositionEncoding::Utf16);
+        let c = client(3, PositionEncoding::Utf8);
+
+        let hover = request(&a, 1, "textDocument/hover");
+        assert!(instance.track_request(&a, &hover).await);
+        assert!(
+            !instance
+                .track_request(&b, &request(&b, 7, "textDocument/hover"))
+                .await
+        );
+        // Positions from clients with another encoding were translated.
+        assert!(
+            instance
+                .track_request(&c, &request(&c, 1, "textDocument/hover"))
+                .await
+        );
+
+        let finished = instance.finish_request(&hover.id, None).await.unwrap();
+        assert_eq!(finished.duplicates, vec![(2, RequestId::Number(7))]);
+        assert!(
+            instance
+                .track_request(&b, &request(&b, 8, "textDocument/hover"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_are_cached_until_invalidated() {
+        let config = Config {
+            response_cache: true,
+            ..Config::default()
+        };
+        let instance = instance(config).await;
+        let a = client(1, PositionEncoding::Utf16);
+
+        let hover = request(&a, 1, "textDocument/hover");
+        assert!(instance.track_request(&a, &hover).await);
+        instance
+            .finish_request(&hover.id, Some(&json!("docs")))
+            .await;
+        assert!(
+            !instance
+                .track_request(&a, &request(&a, 2, "textDocument/hover"))
+                .await
+        );
+
+        // Responses to requests sent before the invalidation are not cached.
+        let hover = request(&a, 3, "textDocument/hover");
+        instance.invalidate_response_cache().await;
+        assert!(instance.track_request(&a, &hover).await);
+        instance.invalidate_response_cache().await;
+        instance
+            .finish_request(&hover.id, Some(&json!("docs")))
+            .await;
+        assert!(
+            instance
+                .track_request(&a, &request(&a, 4, "textDocument/hover"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_are_not_cached() {
+        let config = Config {
+            response_cache: true,
+            ..Config::default()
+        };
+        let instance = instance(config).await;
+        let a = client(1, PositionEncoding::Utf16);
+
+        let tokens = request(&a, 1, "textDocument/semanticTokens/full");
+        assert!(instance.track_request(&a, &tokens).await);
+        let result = json!({ "resultId": "1", "data": [] });
+        instance.finish_request(&tokens.id, Some(&result)).await;
+        assert!(
+            instance
+                .track_request(&a, &request(&a, 2, "textDocument/semanticTokens/full"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn configuration_is_answered_from_cache() {
+        let mut config = Config::default();
+        config.workspace_configuration.insert(
+            "server".into(),
+            json!({ "fake": { "a/b": 1, "c~d": { "e": 2 } } }),
+        );
+        let instance = instance(config).await;
+        let item = |scope_uri: Option<&str>, section: &str| lsp::ConfigurationItem {
+            scope_uri: scope_uri.map(str::to_owned),
+            section: Some(section.into()),
+        };
+
+        let id = RequestId::Number(1);
+        instance.forwarded_requests.lock().await.insert(
+            id.clone(),
+            ForwardedRequest {
+                client_id: 1,
+                configuration_items: Some(vec![
+                    item(None, "check"),
+                    item(Some("file:///ws/a"), "check"),
+                ]),
+            },
+        );
+        let answers = json!(["global", "a"]);
+        assert!(instance.finish_forwarded_request(&id, Some(&answers)).await);
+
+        let items = [
+            item(Some("file:///ws/a"), "check"),
+            item(Some("file:///ws/b"), "check"),
+            item(None, "fake.a/b"),
+            item(None, "fake.c~d.e"),
+            item(None, "fake.missing"),
+        ];
+        assert_eq!(
+            instance.cached_configuration(&items).await,
+            json!(["a", "global", 1, 2, null]),
+        );
+    }
+
+    #[test]
+    fn response_cache_evicts_oldest() {
+        let mut cache = ResponseCache::default();
+        for i in 0..=RESPONSE_CACHE_LIMIT {
+            cache.insert(i.to_string(), json!(i));
+        }
+        assert_eq!(cache.results.len(), RESPONSE_CACHE_LIMIT);
+        assert!(!cache.results.contains_key("0"));
+        assert!(cache.results.contains_key("1"));
+        assert!(cache
+            .results
+            .contains_key(&RESPONSE_CACHE_LIMIT.to_string()));
+    }
+}
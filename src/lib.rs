@@ -1,4 +1,5 @@
 mod client;
+mod document;
 mod instance;
 mod lsp;
 mod socketwrapper;
@@ -23,10 +23,15 @@
 //! client to the server and to pass a server notification to all clients, however there are some
 //! subtypes of notifications defined by the LSP where that could be confusing to the client or
 //! server:
-//! - Cancel notifications - contains an `id` property again, so we could multiplex this like any
-//!   other request
-//! - Progress notifications - contains a `token` property which could be used to identify the
-//!   client but the specification also says it has nothing to do with the request IDs
+//! - Cancel notifications - contains an `id` property again, so we multiplex this like any other
+//!   request
+//! - Progress notifications - contains a `token` property which has nothing to do with the request
+//!   IDs, but tokens supplied by a client in its request can be used to identify the client
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
@@ -79,6 +84,111 @@ pub struct InitializeParams {
     pub workspace_folders: Vec<WorkspaceFolder>,
 }
 
+impl InitializeParams {
+    pub fn client_capabilities(&self) -> ClientCapabilities {
+        ClientCapabilities(Arc::new(self.capabilities.clone().unwrap_or_default()))
+    }
+}
+
+/// Capabilities announced by a client in its `initialize` request
+///
+/// Missing capabilities are treated as not supported like the specification
+/// asks.
+#[derive(Clone, Debug, Default)]
+pub struct ClientCapabilities(Arc<serde_json::Value>);
+
+impl ClientCapabilities {
+    /// Is the capability at the JSON pointer set to `true`
+    fn flag(&self, pointer: &str) -> bool {
+        self.0.pointer(pointer) == Some(&serde_json::Value::Bool(true))
+    }
+
+    /// Position encodings supported by the client in order of preference
+    ///
+    /// Encodings we don't know are skipped, UTF-16 is always supported.
+    pub fn position_encodings(&self) -> Vec<PositionEncoding> {
+        let mut encodings = self
+            .0
+            .pointer("/general/positionEncodings")
+            .and_then(|encodings| encodings.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|encoding| serde_json::from_value(encoding.clone()).ok())
+            .collect::<Vec<_>>();
+        if !encodings.contains(&PositionEncoding::Utf16) {
+            encodings.push(PositionEncoding::Utf16);
+        }
+        encodings
+    }
+
+    /// Does the client accept `window/workDoneProgress/create` requests
+    pub fn work_done_progress(&self) -> bool {
+        self.flag("/window/workDoneProgress")
+    }
+
+    /// Does the client accept `window/showDocument` requests
+    pub fn show_document(&self) -> bool {
+        self.flag("/window/showDocument/support")
+    }
+
+    /// Does the client accept `workspace/applyEdit` requests
+    pub fn apply_edit(&self) -> bool {
+        self.flag("/workspace/applyEdit")
+    }
+
+    /// Does the client accept `workspace/configuration` requests
+    pub fn configuration(&self) -> bool {
+        self.flag("/workspace/configuration")
+    }
+
+    /// Does the client accept a `workspace/*/refresh` request like
+    /// `workspace/semanticTokens/refresh`
+    pub fn refresh_support(&self, method: &str) -> bool {
+        let feature = match method {
+            "workspace/diagnostic/refresh" => "diagnostics",
+            _ => method
+                .strip_prefix("workspace/")
+                .and_then(|method| method.strip_suffix("/refresh"))
+                .unwrap_or(method),
+        };
+        self.flag(&format!("/workspace/{feature}/refreshSupport"))
+    }
+
+    /// Does the client support registering a method dynamically with
+    /// `client/registerCapability`
+    pub fn dynamic_registration(&self, method: &str) -> bool {
+        let capability = match method {
+            "textDocument/didOpen"
+            | "textDocument/didChange"
+            | "textDocument/didClose"
+            | "textDocument/willSave"
+            | "textDocument/willSaveWaitUntil"
+            | "textDocument/didSave" => "textDocument/synchronization",
+            "textDocument/prepareCallHierarchy" => "textDocument/callHierarchy",
+            "textDocument/prepareTypeHierarchy" => "textDocument/typeHierarchy",
+            "notebookDocument/sync" => "notebookDocument/synchronization",
+            "workspace/didCreateFiles"
+            | "workspace/willCreateFiles"
+            | "workspace/didRenameFiles"
+            | "workspace/willRenameFiles"
+            | "workspace/didDeleteFiles"
+            | "workspace/willDeleteFiles" => "workspace/fileOperations",
+            _ => method,
+        };
+        self.flag(&format!("/{capability}/dynamicRegistration"))
+    }
+
+    /// Does the client pull diagnostics with `textDocument/diagnostic`
+    pub fn pull_diagnostics(&self) -> bool {
+        self.0.pointer("/textDocument/diagnostic").is_some()
+    }
+
+    /// Does the client request semantic token deltas
+    pub fn semantic_tokens_delta(&self) -> bool {
+        self.flag("/textDocument/semanticTokens/requests/full/delta")
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientInfo {
@@ -96,6 +206,86 @@ pub struct InitializationOptions {
     pub other_options: serde_json::Map<String, serde_json::Value>,
 }
 
+impl InitializationOptions {
+    /// Hash of the options meant for the language server
+    ///
+    /// The order of object keys doesn't matter and `null` values are treated
+    /// like missing ones.
+    pub fn normalized_hash(&self) -> u64 {
+        fn hash_value(value: &serde_json::Value, hasher: &mut DefaultHasher) {
+            match value {
+                serde_json::Value::Object(object) => hash_object(object, hasher),
+                serde_json::Value::Array(items) => {
+                    hasher.write_u8(b'[');
+                    hasher.write_usize(items.len());
+                    for item in items {
+                        hash_value(item, hasher);
+                    }
+                }
+                value => value.to_string().hash(hasher),
+            }
+        }
+
+        fn hash_object(
+            object: &serde_json::Map<String, serde_json::Value>,
+            hasher: &mut DefaultHasher,
+        ) {
+            let mut entries = object
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .collect::<Vec<_>>();
+            entries.sort_by_key(|(key, _)| *key);
+            hasher.write_u8(b'{');
+            hasher.write_usize(entries.len());
+            for (key, value) in entries {
+                key.hash(hasher);
+                hash_value(value, hasher);
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hash_object(&self.other_options, &mut hasher);
+        hasher.finish()
+    }
+
+    /// Describe how the `other` options differ from these
+    ///
+    /// Returns one line per differing value with its JSON pointer, nested
+    /// objects are compared key by key.
+    pub fn diff(&self, other: &InitializationOptions) -> Vec<String> {
+        fn diff_objects(
+            path: &str,
+            old: &serde_json::Map<String, serde_json::Value>,
+            new: &serde_json::Map<String, serde_json::Value>,
+            differences: &mut Vec<String>,
+        ) {
+            let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+            for key in keys {
+                let path = format!("{path}/{key}");
+                let null = serde_json::Value::Null;
+                match (old.get(key).unwrap_or(&null), new.get(key).unwrap_or(&null)) {
+                    (serde_json::Value::Object(old), serde_json::Value::Object(new)) => {
+                        diff_objects(&path, old, new, differences);
+                    }
+                    (old, new) if old != new => {
+                        differences.push(format!("{path}: {old} -> {new}"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut differences = Vec::new();
+        diff_objects(
+            "",
+            &self.other_options,
+            &other.other_options,
+            &mut differences,
+        );
+        differences
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum TraceValue {
@@ -110,6 +300,20 @@ pub struct WorkspaceFolder {
     pub name: String,
 }
 
+/// Params for `workspace/didChangeWorkspaceFolders` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeWorkspaceFoldersParams {
+    pub event: WorkspaceFoldersChangeEvent,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceFoldersChangeEvent {
+    pub added: Vec<WorkspaceFolder>,
+    pub removed: Vec<WorkspaceFolder>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct InitializeResult {
@@ -119,6 +323,66 @@ pub struct InitializeResult {
     server_info: Option<ServerInfo>,
 }
 
+impl InitializeResult {
+    /// Does the server accept `workspace/didChangeWorkspaceFolders` notifications
+    ///
+    /// `changeNotifications` can also be a string ID which means the server
+    /// is going to register for them dynamically, we treat it as a yes.
+    pub fn supports_workspace_folder_changes(&self) -> bool {
+        let folders = &self.capabilities["workspace"]["workspaceFolders"];
+        folders["supported"] == true
+            && match &folders["changeNotifications"] {
+                serde_json::Value::Bool(enabled) => *enabled,
+                serde_json::Value::String(_) => true,
+                _ => false,
+            }
+    }
+
+    /// Position encoding the server has selected, UTF-16 if it didn't say
+    pub fn position_encoding(&self) -> PositionEncoding {
+        serde_json::from_value(self.capabilities["positionEncoding"].clone()).unwrap_or_default()
+    }
+
+    /// Result tailored for a client which may not support everything the
+    /// client the server was initialized with does
+    ///
+    /// Semantic token deltas are disabled for clients using a different
+    /// position encoding than the server, they edit the token data the server
+    /// has computed for its own encoding so we couldn't translate them.
+    pub fn for_client(
+        &self,
+        capabilities: &ClientCapabilities,
+        encoding: PositionEncoding,
+    ) -> InitializeResult {
+        let mut result = self.clone();
+        let translated = encoding != self.position_encoding();
+        let Some(server_capabilities) = result.capabilities.as_object_mut() else {
+            return result;
+        };
+
+        if translated {
+            server_capabilities.insert(
+                "positionEncoding".into(),
+                serde_json::to_value(encoding).unwrap(),
+            );
+        }
+        if !capabilities.pull_diagnostics() {
+            server_capabilities.remove("diagnosticProvider");
+        }
+        if translated || !capabilities.semantic_tokens_delta() {
+            if let Some(full) = result
+                .capabilities
+                .pointer_mut("/semanticTokensProvider/full")
+            {
+                if full.is_object() {
+                    *full = serde_json::Value::Bool(true);
+                }
+            }
+        }
+        result
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ServerInfo {
     name: String,
@@ -175,6 +439,70 @@ pub struct TextDocumentItem {
     pub text: String,
 }
 
+/// Params for `textDocument/didChange` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeTextDocumentParams {
+    pub text_document: VersionedTextDocumentIdentifier,
+    pub content_changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionedTextDocumentIdentifier {
+    pub uri: String,
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TextDocumentContentChangeEvent {
+    /// Range of the document that changed, the whole document is replaced if
+    /// it's missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range_length: Option<u32>,
+
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+/// Units `Position::character` is counted in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[default]
+    #[serde(rename = "utf-16")]
+    Utf16,
+    #[serde(rename = "utf-32")]
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Length of a character in this encoding's units
+    pub fn char_len(self, ch: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => ch.len_utf8(),
+            PositionEncoding::Utf16 => ch.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
 /// Params for `textDocument/didClose` notification
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -187,3 +515,182 @@ pub struct DidCloseTextDocumentParams {
 pub struct TextDocumentIdentifier {
     pub uri: String,
 }
+
+/// Params for `$/cancelRequest` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelParams {
+    pub id: jsonrpc::RequestId,
+}
+
+/// Params for `textDocument/publishDiagnostics` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishDiagnosticsParams {
+    pub uri: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+
+    pub diagnostics: Vec<serde_json::Value>,
+}
+
+/// Token identifying a `$/progress` stream
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ProgressToken {
+    Number(i64),
+    String(String),
+}
+
+/// Params for `$/progress` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgressParams {
+    pub token: ProgressToken,
+    pub value: serde_json::Value,
+}
+
+/// Params for `window/workDoneProgress/create` request
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkDoneProgressCreateParams {
+    pub token: ProgressToken,
+}
+
+/// Result for the `workspace/applyEdit` request
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplyWorkspaceEditResult {
+    pub applied: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+}
+
+/// Params for `workspace/configuration` request
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationParams {
+    pub items: Vec<ConfigurationItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationItem {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope_uri: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+}
+
+/// Params for `window/showMessage` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ShowMessageParams {
+    #[serde(rename = "type")]
+    pub typ: MessageType,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(transparent)]
+pub struct MessageType(pub i64);
+
+impl MessageType {
+    pub const ERROR: MessageType = MessageType(1);
+    pub const WARNING: MessageType = MessageType(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn capabilities(value: Value) -> ClientCapabilities {
+        ClientCapabilities(Arc::new(value))
+    }
+
+    #[test]
+    fn client_capabilities() {
+        let caps = capabilities(json!({
+            "general": { "positionEncodings": ["utf-32", "utf-7", "utf-8"] },
+            "textDocument": { "synchronization": { "dynamicRegistration": true } },
+            "workspace": {
+                "diagnostics": { "refreshSupport": true },
+                "fileOperations": { "dynamicRegistration": true },
+                "inlayHint": { "refreshSupport": false },
+            },
+        }));
+        assert_eq!(
+            caps.position_encodings(),
+            [
+                PositionEncoding::Utf32,
+                PositionEncoding::Utf8,
+                PositionEncoding::Utf16,
+            ],
+        );
+        assert!(caps.dynamic_registration("textDocument/didSave"));
+        assert!(caps.dynamic_registration("workspace/willRenameFiles"));
+        assert!(!caps.dynamic_registration("workspace/didChangeWatchedFiles"));
+        assert!(caps.refresh_support("workspace/diagnostic/refresh"));
+        assert!(!caps.refresh_support("workspace/inlayHint/refresh"));
+        assert!(!caps.refresh_support("workspace/codeLens/refresh"));
+        assert!(!caps.work_done_progress());
+    }
+
+    #[test]
+    fn initialization_options() {
+        let options = |value: Value| InitializationOptions {
+            lsp_mux: None,
+            other_options: serde_json::from_value(value).unwrap(),
+        };
+        let a = options(json!({ "cargo": { "features": ["a"], "target": null }, "check": true }));
+        let b = options(json!({ "check": true, "cargo": { "features": ["a"] } }));
+        let c = options(json!({ "cargo": { "features": ["b"] }, "procMacro": false }));
+
+        assert_eq!(a.normalized_hash(), b.normalized_hash());
+        assert_ne!(a.normalized_hash(), c.normalized_hash());
+
+        assert!(a.diff(&b).is_empty());
+        assert_eq!(
+            b.diff(&c),
+            [
+                r#"/cargo/features: ["a"] -> ["b"]"#,
+                "/check: true -> null",
+                "/procMacro: null -> false",
+            ],
+        );
+    }
+
+    #[test]
+    fn initialize_result_for_client() {
+        let result = InitializeResult {
+            capabilities: json!({
+                "positionEncoding": "utf-8",
+                "diagnosticProvider": { "interFileDependencies": true },
+                "semanticTokensProvider": { "full": { "delta": true } },
+            }),
+            server_info: None,
+        };
+
+        let caps = capabilities(json!({
+            "general": { "positionEncodings": ["utf-8"] },
+            "textDocument": {
+                "diagnostic": {},
+                "semanticTokens": { "requests": { "full": { "delta": true } } },
+            },
+        }));
+        let tailored = result.for_client(&caps, PositionEncoding::Utf8);
+        assert_eq!(tailored.capabilities, result.capabilities);
+
+        let tailored = result.for_client(&capabilities(json!({})), PositionEncoding::Utf16);
+        let expected = json!({
+            "positionEncoding": "utf-16",
+            "semanticTokensProvider": { "full": true },
+        });
+        assert_eq!(tailored.capabilities, expected);
+    }
+}
@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use tracing::warn;
 
 use super::jsonrpc::RequestId;
+use super::ProgressToken;
 
 /// Additional metadata inserted into LSP RequestId
 pub enum Tag {
@@ -85,6 +86,35 @@ impl RequestId {
     }
 }
 
+impl ProgressToken {
+    /// Serializes the token to a string and prepends the ID of the client
+    /// which supplied it with a request
+    pub fn tag(&self, client_id: usize) -> ProgressToken {
+        let token = match self {
+            ProgressToken::Number(number) => format!("n:{number}"),
+            ProgressToken::String(string) => format!("s:{string}"),
+        };
+        ProgressToken::String(format!("client_id:{client_id}:{token}"))
+    }
+
+    /// Attempts to parse the client ID out of the token
+    ///
+    /// Returns `None` for tokens created by the server.
+    pub fn untag(&self) -> Option<(usize, ProgressToken)> {
+        let ProgressToken::String(input) = self else {
+            return None;
+        };
+        let (client_id, rest) = input.strip_prefix("client_id:")?.split_once(':')?;
+        let client_id = usize::from_str(client_id).ok()?;
+        let token = match rest.split_once(':')? {
+            ("n", number) => ProgressToken::Number(number.parse().ok()?),
+            ("s", string) => ProgressToken::String(string.to_owned()),
+            _ => return None,
+        };
+        Some((client_id, token))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LspMuxOptions {
     /// Version number of the protocol
@@ -147,10 +177,38 @@ pub enum Request {
     /// For rust-analyzer send the `rust-analyzer/reloadWorkspace` extension request.
     /// Do nothing for other language servers.
     Reload {
-        /// Selects instance with the longest path where
-        /// `cwd.starts_with(workspace_root)` is true
+        /// Selects instance with the longest workspace folder path where
+        /// `cwd.starts_with(workspace_folder)` is true
         cwd: String,
     },
+
+    /// Restart an instance
+    ///
+    /// Kill the language server and start a new one with the same
+    /// configuration, connected clients stay connected.
+    Restart {
+        /// Selects instance with the longest workspace folder path where
+        /// `cwd.starts_with(workspace_folder)` is true
+        cwd: String,
+    },
+
+    /// Stop instances
+    ///
+    /// Shut the language servers down and disconnect their clients.
+    Stop {
+        /// Selects instance with the longest workspace folder path where
+        /// `cwd.starts_with(workspace_folder)` is true
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+
+        /// Selects instance by the language server PID
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects all instances
+        #[serde(default)]
+        all: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -167,6 +225,7 @@ pub struct Instance {
     pub args: Vec<String>,
     pub env: BTreeMap<String, String>,
     pub workspace_root: String,
+    pub workspace_folders: Vec<String>,
     pub registered_dyn_capabilities: Vec<String>,
     pub last_used: i64,
     pub clients: Vec<Client>,
@@ -177,13 +236,20 @@ pub struct Instance {
 pub struct Client {
     pub id: usize,
     pub files: Vec<String>,
+    pub workspace_folders: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RestartResponse {
+    /// PID of the new language server process
+    pub pid: u32,
 }
 
-#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct StopResponse {
-    pub instance: Instance,
+    pub instances: Vec<Instance>,
 }
 
 #[cfg(test)]
@@ -235,6 +301,34 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn tagged_request_ids() {
+        use super::Tag;
+        use crate::lsp::jsonrpc::RequestId;
+
+        let ids = [RequestId::Number(7), RequestId::String("a:b".into())];
+        for id in ids {
+            let tagged = id.tag(Tag::ClientId(3));
+            assert!(matches!(tagged.untag(), (Some(Tag::ClientId(3)), inner) if inner == id));
+            let tagged = id.tag(Tag::Forward);
+            assert!(matches!(tagged.untag(), (Some(Tag::Forward), inner) if inner == id));
+        }
+    }
+
+    #[test]
+    fn tagged_progress_tokens() {
+        use crate::lsp::ProgressToken;
+
+        let tokens = [
+            ProgressToken::Number(7),
+            ProgressToken::String("a:b".into()),
+        ];
+        for token in tokens {
+            assert_eq!(token.tag(3).untag(), Some((3, token.clone())));
+            assert_eq!(token.untag(), None);
+        }
+    }
+
     #[test]
     #[should_panic = "missing field `version`"]
     fn missing_version() {
@@ -73,6 +73,24 @@ pub struct Error {
     pub data: Option<serde_json::Value>,
 }
 
+impl Error {
+    /// Invalid method parameters
+    pub const INVALID_PARAMS: i64 = -32602;
+
+    /// Internal JSON-RPC error
+    pub const INTERNAL_ERROR: i64 = -32603;
+
+    /// The client cancelled a request and the server has detected the cancel
+    pub const REQUEST_CANCELLED: i64 = -32800;
+
+    /// The server detected that the content of a document got modified
+    /// outside normal conditions and the result of a request is invalid
+    pub const CONTENT_MODIFIED: i64 = -32801;
+
+    /// A request failed but it was syntactically correct
+    pub const REQUEST_FAILED: i64 = -32803;
+}
+
 #[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(untagged)]
@@ -81,7 +99,7 @@ pub enum Params {
     ByName(serde_json::Map<String, serde_json::Value>),
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum RequestId {
     Number(i64),
@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, process};
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
@@ -51,6 +51,30 @@ enum Cmd {
     /// For rust-analyzer send the `rust-analyzer/reloadWorkspace` extension request.
     /// Do nothing for other language servers.
     Reload {},
+
+    /// Restart the language server
+    ///
+    /// Kill the language server process of the instance for the current
+    /// directory and start a new one, connected editors stay connected.
+    Restart {},
+
+    /// Stop language server instances
+    ///
+    /// Shut the language server down and disconnect its clients. Stops the
+    /// instance for the current directory if no other option is given.
+    Stop {
+        /// Stop the instance for this directory
+        #[arg(long, conflicts_with_all = ["pid", "all"])]
+        cwd: Option<String>,
+
+        /// Stop the instance with this language server PID
+        #[arg(long, conflicts_with = "all")]
+        pid: Option<u32>,
+
+        /// Stop all instances
+        #[arg(long)]
+        all: bool,
+    },
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -73,13 +97,30 @@ async fn main() -> Result<()> {
 
     match cli.command {
         Some(Cmd::Server {}) => server::run(&config).await,
-        Some(Cmd::Client { server, args }) => proxy::run(&config, server, args).await,
+        Some(Cmd::Client { server, args }) => run_proxy(&config, server, args).await,
         Some(Cmd::Status { json }) => ext::status(&config, json).await,
         Some(Cmd::Config {}) => ext::config(&config),
         Some(Cmd::Reload {}) => ext::reload(&config).await,
+        Some(Cmd::Restart {}) => ext::restart(&config).await,
+        Some(Cmd::Stop { cwd, pid, all }) => ext::stop(&config, cwd, pid, all).await,
         None => {
             let server_path = env::var("RA_MUX_SERVER").unwrap_or_else(|_| "rust-analyzer".into());
-            proxy::run(&config, server_path, vec![]).await
+            run_proxy(&config, server_path, vec![]).await
+        }
+    }
+}
+
+/// Run the proxy and exit the process as soon as it's done
+///
+/// Tokio would wait for the blocking stdin read to finish before shutting down
+/// the runtime, but the editor can keep our stdin open after the server has
+/// closed the connection.
+async fn run_proxy(config: &Config, server: String, args: Vec<String>) -> ! {
+    match proxy::run(config, server, args).await {
+        Ok(()) => process::exit(0),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            process::exit(1);
         }
     }
 }
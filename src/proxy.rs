@@ -1,8 +1,10 @@
 use std::collections::BTreeMap;
 use std::env;
+use std::pin::pin;
 
 use anyhow::{bail, Context as _, Result};
-use tokio::io::{self, BufStream};
+use tokio::io::{self, AsyncWriteExt, BufStream};
+use tokio::select;
 
 use crate::config::Config;
 use crate::lsp::ext::{LspMuxOptions, Request};
@@ -61,8 +63,24 @@ pub async fn run(config: &Config, server: String, args: Vec<String>) -> Result<(
         .context("forward initialize request")?;
 
     // Forward everything else unmodified.
-    io::copy_bidirectional(&mut stream, &mut stdio)
-        .await
-        .context("io error")?;
+    let (mut stdin, mut stdout) = io::split(stdio);
+    let (mut stream_read, mut stream_write) = io::split(stream);
+    let mut server_to_client = pin!(io::copy(&mut stream_read, &mut stdout));
+    let client_to_server = async {
+        io::copy(&mut stdin, &mut stream_write).await?;
+        stream_write.shutdown().await
+    };
+
+    // Return as soon as the server closes the connection, for example when the
+    // instance is stopped, even if the editor keeps our stdin open.
+    select! {
+        res = &mut server_to_client => {
+            res.context("io error")?;
+        }
+        res = client_to_server => {
+            res.context("io error")?;
+            server_to_client.await.context("io error")?;
+        }
+    }
     Ok(())
 }
@@ -1,12 +1,15 @@
+use std::future;
+use std::pin::pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::{Context, Result};
-use tokio::task;
+use tokio::{select, task};
 use tracing::{error, info, info_span, warn, Instrument};
 
 use crate::client;
 use crate::config::Config;
 use crate::instance::InstanceMap;
+use crate::lsp::MessageType;
 use crate::socketwrapper::Listener;
 
 pub async fn run(config: &Config) -> Result<()> {
@@ -14,10 +17,16 @@ pub async fn run(config: &Config) -> Result<()> {
     let next_client_id = AtomicUsize::new(0);
     let next_client_id = || next_client_id.fetch_add(1, Ordering::Relaxed);
 
+    let mut shutdown = pin!(shutdown_signal());
+
     let listener = Listener::bind(&config.listen).await.context("listen")?;
     info!(socket = ?config.listen, "listening");
     loop {
-        match listener.accept().await {
+        let accepted = select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        match accepted {
             Ok((socket, _addr)) => {
                 let client_id = next_client_id();
                 let instance_map = instance_map.clone();
@@ -44,4 +53,63 @@ pub async fn run(config: &Config) -> Result<()> {
             },
         }
     }
+
+    info!("shutting down");
+
+    let instances = instance_map.lock().await.all();
+    let mut stopping = task::JoinSet::new();
+    for instance in instances {
+        stopping.spawn(async move {
+            instance
+                .show_message(MessageType::WARNING, "ra-multiplex server is shutting down")
+                .await;
+            instance.stop().await
+        });
+    }
+    while let Some(res) = stopping.join_next().await {
+        if let Err(err) = res {
+            error!(?err, "error stopping instance");
+        }
+    }
+
+    if let Err(err) = listener.unbind(&config.listen) {
+        error!(?err, "error closing listener");
+    }
+
+    info!("shut down");
+    Ok(())
+}
+
+/// Wait for SIGTERM or SIGINT
+///
+/// Never completes if the signal handlers can't be registered, the server
+/// keeps running without a graceful shutdown then.
+async fn shutdown_signal() {
+    if let Err(err) = wait_for_signal().await {
+        error!(
+            ?err,
+            "cannot listen for shutdown signals, continuing without them"
+        );
+        future::pending().await
+    }
+}
+
+async fn wait_for_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).context("listen for SIGTERM")?;
+        let mut sigint = signal(SignalKind::interrupt()).context("listen for SIGINT")?;
+        select! {
+            _ = sigterm.recv() => info!("received SIGTERM"),
+            _ = sigint.recv() => info!("received SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.context("listen for ctrl-c")?;
+        info!("received ctrl-c");
+    }
+    Ok(())
 }
@@ -258,6 +258,20 @@ impl Listener {
         }
     }
 
+    /// Stop listening, unix socket files are removed if they still exist
+    pub fn unbind(self, addr: &Address) -> Result<()> {
+        drop(self);
+        match addr {
+            Address::Tcp(..) => Ok(()),
+            #[cfg(target_family = "unix")]
+            Address::Unix(path) => match fs::remove_file(path) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e).with_context(|| format!("removing unix socket file {path:?}")),
+            },
+        }
+    }
+
     pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
         match self {
             Listener::Tcp(tcp) => {